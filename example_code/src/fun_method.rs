@@ -27,3 +27,139 @@ impl T for R {
 pub fn _virt(ob: &dyn T) {
     ob.bla();
 }
+
+impl Default for S {
+    fn default() -> Self {
+        S::new()
+    }
+}
+
+impl S {
+    pub fn new() -> S {
+        S
+    }
+}
+
+pub fn _assoc(s: &S) {
+    S::met(s);
+    <S as T>::bla(s);
+    let _d: S = Default::default();
+}
+
+pub fn _indirect(s: &S, f: fn(&S), g: &dyn Fn(&S)) -> usize {
+    let h = |s: &S| s.met();
+    h(s);
+    f(s);
+    g(s);
+    [1, 2, 3].iter().map(|x| x + S::new()._count()).sum()
+}
+
+impl S {
+    pub fn _count(&self) -> usize {
+        1
+    }
+}
+
+pub fn generic<X: T>(x: &X) {
+    x.bla();
+}
+
+impl std::ops::Add for S {
+    type Output = S;
+
+    fn add(self, _other: S) -> S {
+        S
+    }
+}
+
+impl PartialEq for S {
+    fn eq(&self, _other: &S) -> bool {
+        true
+    }
+}
+
+pub fn _operator(s: S) -> bool {
+    let sum = s + S::new();
+    sum == S::new()
+}
+
+pub struct Guard(S);
+
+impl Drop for Guard {
+    fn drop(&mut self) {}
+}
+
+impl std::ops::Deref for Guard {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+pub struct Error;
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(_e: std::num::ParseIntError) -> Error {
+        Error
+    }
+}
+
+pub fn _implicit(input: &str) -> Result<usize, Error> {
+    let guard = Guard(S::new());
+    guard.met();
+    let n: usize = input.parse()?;
+    Ok(n + guard._count())
+}
+
+#[derive(Clone, Debug)]
+pub struct Countdown(usize);
+
+impl Iterator for Countdown {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        self.0 = self.0.checked_sub(1)?;
+        Some(self.0)
+    }
+}
+
+pub fn _desugared() -> usize {
+    let mut total = 0;
+    let countdown = Countdown(3);
+    for n in countdown.clone() {
+        total += n;
+    }
+    total
+}
+
+async fn _answer() -> usize {
+    S::new()._count()
+}
+
+pub async fn _awaited() -> usize {
+    _answer().await
+}
+
+macro_rules! new_s {
+    () => {
+        S::new()
+    };
+}
+
+pub fn _macro() -> usize {
+    println!("{}", new_s!()._count());
+    new_s!()._count()
+}
+
+const fn _seven() -> usize {
+    7
+}
+
+pub const _SEVEN: usize = _seven();
+
+pub static _TABLE: [usize; _seven()] = [0; _seven()];
+
+pub enum _Discriminant {
+    A = _seven() as isize,
+}
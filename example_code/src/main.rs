@@ -1,9 +1,16 @@
 mod fun_method;
 
-use fun_method::{S, T};
+use fun_method::{generic, R, S, T};
 
 fn main() {
     let s = S {};
     s.met();
     s.bla();
+    generic(&R);
+    fun_method::_virt(&s);
+    fun_method::_operator(s);
+    let _ = fun_method::_implicit("1");
+    fun_method::_desugared();
+    let _ = fun_method::_awaited();
+    fun_method::_macro();
 }
@@ -5,27 +5,84 @@ extern crate rustc_interface;
 
 use rustc_driver::{Callbacks, Compilation};
 use rustc_interface::Queries;
-use rustc_interface::interface::Compiler;
+use rustc_interface::interface::{Compiler, Config};
 
-use callgraph::{analyze, compile_time_sysroot};
+use std::path::Path;
 
-struct CallgraphCallbacks;
+use callgraph::{analyze, compile_time_sysroot, link, output, resolve_dynamic_calls, Backend, Callgraph, Options};
+
+struct CallgraphCallbacks {
+    options: Options,
+}
 
 impl Callbacks for CallgraphCallbacks {
 
+    fn config(&mut self, config: &mut Config) {
+        if self.options.backend == Backend::Mir {
+            // Inlined calls would be missing from the optimized MIR, and
+            // constructors with constant arguments would be folded to constants.
+            config.opts.unstable_opts.inline_mir = Some(false);
+            config.opts.unstable_opts.mir_opt_level = Some(0);
+        }
+    }
+
     fn after_analysis<'tcx>(&mut self, _compiler: &Compiler, queries: &'tcx Queries<'tcx>) -> Compilation {
         // let expanded_crate = &compiler.expansion().unwrap().peek().0;
-        queries.global_ctxt().unwrap().enter(|tcx| {
-            analyze(&tcx);
-        });
+        let mut callgraph = queries.global_ctxt().unwrap().enter(|tcx| analyze(&tcx, &self.options));
+        resolve_dynamic_calls(&mut callgraph, &self.options);
+        eprintln!("callgraph of {}: {}", callgraph.crates.join(", "), callgraph.summary());
+        if let Err(e) = output::write(&callgraph, &self.options) {
+            eprintln!("failed to write callgraph: {}", e);
+            std::process::exit(1);
+        }
 
         Compilation::Stop
     }
 }
 
+/// `callgraph link <callgraph.json>...`: links callgraphs written with
+/// `--output-format json`, e.g. the fragments of `cargo callgraph`, into one.
+fn link_files(paths: &[String], options: &Options) {
+    if paths.is_empty() {
+        eprintln!("no callgraphs to link");
+        std::process::exit(1);
+    }
+    let mut fragments = vec![];
+    for path in paths {
+        match Callgraph::read(Path::new(path)) {
+            Ok(fragment) => fragments.push(fragment),
+            Err(e) => {
+                eprintln!("failed to read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut callgraph = link(fragments);
+    resolve_dynamic_calls(&mut callgraph, options);
+    eprintln!("callgraph: {}", callgraph.summary());
+    if let Err(e) = output::write(&callgraph, options) {
+        eprintln!("failed to write callgraph: {}", e);
+        std::process::exit(1);
+    }
+}
+
 fn main() {
     let mut args: Vec<_> = std::env::args().collect();
 
+    // Take out our own flags, rustc would reject them.
+    let options = match Options::from_args(&mut args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.get(1).is_some_and(|arg| arg == "link") {
+        link_files(&args[2..], &options);
+        return;
+    }
+
     // Make sure we use the right default sysroot. The default sysroot is wrong,
     // because `get_or_default_sysroot` in `librustc_session` bases that on `current_exe`.
     //
@@ -41,8 +98,10 @@ fn main() {
         }
     }
 
-    let mut calls = CallgraphCallbacks;
+    let mut calls = CallgraphCallbacks { options };
 
     let run_compiler = rustc_driver::RunCompiler::new(&args, &mut calls);
-    run_compiler.run();
+    if run_compiler.run().is_err() {
+        std::process::exit(1);
+    }
 }
@@ -1,15 +1,20 @@
 
-///! This implementation is based on `cargo-miri`
-///! https://github.com/rust-lang/miri/blob/master/src/bin/cargo-miri.rs
+//! This implementation is based on `cargo-miri`
+//! https://github.com/rust-lang/miri/blob/master/src/bin/cargo-miri.rs
 
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 
+use cargo_metadata::{DependencyKind, PackageId};
 use rustc_version::VersionMeta;
 
+use callgraph::options::{take_flag, take_flag_values};
+use callgraph::{link, output, resolve_dynamic_calls, Callgraph, DynamicCalls, Options, OutputFormat};
+
 use wait_timeout::ChildExt;
 
 fn show_error(msg: impl AsRef<str>) -> ! {
@@ -41,10 +46,9 @@ fn get_arg_flag_value(name: &str) -> Option<String> {
         if suffix.is_empty() {
             // This argument is exactly `name`; the next one is the value.
             return args.next();
-        } else if suffix.starts_with('=') {
+        } else if let Some(value) = suffix.strip_prefix('=') {
             // This argument is `name=value`; get the value.
-            // Strip leading `=`.
-            return Some(suffix[1..].to_owned());
+            return Some(value.to_owned());
         }
     }
 }
@@ -72,10 +76,9 @@ where
                 Some(arg) => arg,
                 None => return false,
             }
-        } else if suffix.starts_with('=') {
+        } else if let Some(value) = suffix.strip_prefix('=') {
             // This argument is `name=value`; get the value.
-            // Strip leading `=`.
-            suffix[1..].to_owned()
+            value.to_owned()
         } else {
             return false;
         };
@@ -86,6 +89,17 @@ where
     }
 }
 
+/// Gets the value of a `-C name=value` codegen option.
+fn get_codegen_option(name: &str) -> Option<String> {
+    // Stop searching at `--`.
+    let args: Vec<String> = std::env::args().take_while(|val| val != "--").collect();
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        let option = if arg == "-C" { args.get(i + 1)? } else { arg.strip_prefix("-C")? };
+        option.strip_prefix(&prefix).map(str::to_owned)
+    })
+}
+
 /// Finds the first argument ends with `.rs`.
 fn get_first_arg_with_rs_suffix() -> Option<String> {
     // Stop searching at `--`.
@@ -98,7 +112,69 @@ fn version_info() -> VersionMeta {
         .expect("failed to determine underlying rustc version of Callgraph")
 }
 
-fn cargo_package() -> cargo_metadata::Package {
+/// Which workspace members to analyze, from `-p`/`--package`, `--workspace` and `--exclude`.
+struct PackageSelection {
+    packages: Vec<String>,
+    workspace: bool,
+    exclude: Vec<String>,
+}
+
+impl PackageSelection {
+    /// Removes the selection flags from `args`, we pass `-p` to `cargo` for each package ourselves.
+    fn from_args(args: &mut Vec<String>) -> Result<PackageSelection, String> {
+        let mut packages = take_flag_values(args, "-p")?;
+        packages.extend(take_flag_values(args, "--package")?);
+        // `--all` is the deprecated spelling of `--workspace`
+        let workspace = take_flag(args, "--workspace") | take_flag(args, "--all");
+        let exclude = take_flag_values(args, "--exclude")?;
+        if !exclude.is_empty() && !workspace {
+            return Err("`--exclude` can only be used together with `--workspace`".to_owned());
+        }
+        Ok(PackageSelection {
+            packages,
+            workspace,
+            exclude,
+        })
+    }
+}
+
+/// Which targets besides lib and bins to analyze, from `--tests`, `--benches`,
+/// `--examples` and `--all-targets`.
+struct TargetSelection {
+    tests: bool,
+    benches: bool,
+    examples: bool,
+}
+
+impl TargetSelection {
+    /// Removes the selection flags from `args`, we select the targets for `cargo` ourselves.
+    fn from_args(args: &mut Vec<String>) -> TargetSelection {
+        let all_targets = take_flag(args, "--all-targets");
+        TargetSelection {
+            tests: take_flag(args, "--tests") || all_targets,
+            benches: take_flag(args, "--benches") || all_targets,
+            examples: take_flag(args, "--examples") || all_targets,
+        }
+    }
+
+    fn is_selected(&self, kind: &TargetKind) -> bool {
+        match kind {
+            TargetKind::Library | TargetKind::Bin => true,
+            TargetKind::Example => self.examples,
+            TargetKind::Test => self.tests,
+            TargetKind::Bench => self.benches,
+            TargetKind::Unknown => false,
+        }
+    }
+
+    /// Whether a selected target is built with the dev-dependencies.
+    fn uses_dev_dependencies(&self) -> bool {
+        self.tests || self.benches || self.examples
+    }
+}
+
+/// Returns the selected packages and the metadata of the workspace.
+fn cargo_packages(selection: &PackageSelection) -> (Vec<cargo_metadata::Package>, cargo_metadata::Metadata) {
     // We need to get the manifest, and then the metadata, to enumerate targets.
     let manifest_path =
         get_arg_flag_value("--manifest-path").map(|m| Path::new(&m).canonicalize().unwrap());
@@ -107,18 +183,43 @@ fn cargo_package() -> cargo_metadata::Package {
     if let Some(manifest_path) = &manifest_path {
         cmd.manifest_path(manifest_path);
     }
-    let mut metadata = match cmd.exec() {
+    let metadata = match cmd.exec() {
         Ok(metadata) => metadata,
         Err(e) => show_error(format!("Could not obtain Cargo metadata\n{}", e)),
     };
     let current_dir = std::env::current_dir();
 
-    let package_index = metadata
-        .packages
+    let members: Vec<cargo_metadata::Package> =
+        metadata.workspace_packages().into_iter().cloned().collect();
+
+    if selection.workspace {
+        let packages = members
+            .into_iter()
+            .filter(|package| !selection.exclude.contains(&package.name))
+            .collect();
+        return (packages, metadata);
+    }
+
+    if !selection.packages.is_empty() {
+        let packages = selection
+            .packages
+            .iter()
+            .map(|name| {
+                members
+                    .iter()
+                    .find(|package| &package.name == name)
+                    .cloned()
+                    .unwrap_or_else(|| show_error(format!("package `{}` not found in workspace", name)))
+            })
+            .collect();
+        return (packages, metadata);
+    }
+
+    let package = members
         .iter()
-        .position(|package| {
+        .find(|package| {
             let package_manifest_path = Path::new(&package.manifest_path);
-            
+
             if let Some(manifest_path) = &manifest_path {
                 package_manifest_path == manifest_path
             } else {
@@ -131,11 +232,99 @@ fn cargo_package() -> cargo_metadata::Package {
                 package_manifest_directory == current_dir
             }
         })
-        .unwrap_or_else(|| {
-            show_error("This seems to be a workspace, which is not supported by cargo-callgraph");
-        });
+        .cloned();
 
-    metadata.packages.remove(package_index)
+    match package {
+        Some(package) => (vec![package], metadata),
+        // At the root of a virtual workspace, analyze all members like `cargo check` does.
+        None => (members, metadata),
+    }
+}
+
+/// The crate name of the lib of `package`, if it has one that is built for
+/// the target, i.e. not a proc macro.
+fn lib_crate_name(package: &cargo_metadata::Package) -> Option<String> {
+    package
+        .targets
+        .iter()
+        .find(|target| target.kind.iter().any(|s| TargetKind::is_lib_str(s)))
+        .map(|target| target.name.replace('-', "_"))
+}
+
+/// Returns the dependencies of `roots` up to `depth` levels deep, for `--extern-depth`
+/// and `--whole-program`. The dev-dependencies of `roots` are included if
+/// `dev_dependencies` is set.
+fn extern_packages(
+    metadata: &cargo_metadata::Metadata,
+    roots: &[cargo_metadata::Package],
+    depth: usize,
+    dev_dependencies: bool,
+) -> Vec<cargo_metadata::Package> {
+    let Some(resolve) = &metadata.resolve else {
+        return vec![];
+    };
+    let nodes: HashMap<&PackageId, &cargo_metadata::Node> = resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+    let mut seen: HashSet<&PackageId> = roots.iter().map(|package| &package.id).collect();
+    let mut level: Vec<&PackageId> = roots.iter().map(|package| &package.id).collect();
+    let mut found = vec![];
+    for i in 0..depth {
+        if level.is_empty() {
+            break;
+        }
+        let mut next = vec![];
+        for id in level {
+            let deps = nodes.get(id).map(|node| node.deps.as_slice()).unwrap_or_default();
+            for dep in deps {
+                // dev-dependencies only count for the analyzed packages when their
+                // tests, benches or examples are built, build dependencies are not
+                // part of the program
+                let followed = dep.dep_kinds.is_empty()
+                    || dep.dep_kinds.iter().any(|info| match info.kind {
+                        DependencyKind::Normal => true,
+                        DependencyKind::Development => dev_dependencies && i == 0,
+                        _ => false,
+                    });
+                if followed && seen.insert(&dep.pkg) {
+                    next.push(&dep.pkg);
+                }
+            }
+        }
+        found.extend(next.iter().copied());
+        level = next;
+    }
+    metadata
+        .packages
+        .iter()
+        .filter(|package| found.contains(&&package.id) && lib_crate_name(package).is_some())
+        .cloned()
+        .collect()
+}
+
+/// The most recent callgraph of the dependency `crate_name` in `dir`. There
+/// is one per build of the crate, e.g. with and without `--profile test`,
+/// named after the crate and the `-C metadata` hash of the build.
+fn newest_extern_fragment(dir: &Path, crate_name: &str) -> Option<PathBuf> {
+    extern_fragments(dir, crate_name)
+        .into_iter()
+        .max_by_key(|path| path.metadata().and_then(|metadata| metadata.modified()).ok())
+}
+
+/// All callgraphs of the dependency `crate_name` in `dir`.
+fn extern_fragments(dir: &Path, crate_name: &str) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            stem.and_then(|stem| stem.rsplit_once('-')).is_some_and(|(name, hash)| {
+                // the fragments of the targets and packages are not hashes
+                name == crate_name && !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+            })
+        })
+        .collect()
 }
 
 /// Returns the path to the `callgraph` binary
@@ -201,7 +390,7 @@ fn clean_package(package_name: &str) {
         .expect("failed to wait for cargo?");
 
     if !exit_status.success() {
-        show_error(format!("cargo clean failed"));
+        show_error("cargo clean failed");
     }
 }
 
@@ -247,6 +436,9 @@ fn main() {
 enum TargetKind {
     Library = 0,
     Bin,
+    Example,
+    Test,
+    Bench,
     Unknown,
 }
 
@@ -260,10 +452,15 @@ impl From<&cargo_metadata::Target> for TargetKind {
     fn from(target: &cargo_metadata::Target) -> Self {
         if target.kind.iter().any(|s| TargetKind::is_lib_str(s)) {
             TargetKind::Library
-        } else if let Some("bin") = target.kind.get(0).map(|s| s.as_ref()) {
+        } else if let Some("bin") = target.kind.first().map(|s| s.as_ref()) {
             TargetKind::Bin
         } else {
-            TargetKind::Unknown
+            match target.kind.first().map(|s| s.as_ref()) {
+                Some("example") => TargetKind::Example,
+                Some("test") => TargetKind::Test,
+                Some("bench") => TargetKind::Bench,
+                _ => TargetKind::Unknown,
+            }
         }
     }
 }
@@ -276,6 +473,9 @@ impl Display for TargetKind {
             match self {
                 TargetKind::Library => "lib",
                 TargetKind::Bin => "bin",
+                TargetKind::Example => "example",
+                TargetKind::Test => "test",
+                TargetKind::Bench => "bench",
                 TargetKind::Unknown => "unknown",
             }
         )
@@ -288,110 +488,236 @@ fn in_cargo_callgraph() {
     // Some basic sanity checks
     test_sysroot_consistency();
 
+    // Skip `cargo callgraph`. Everything up to the first `--` is for `cargo`,
+    // the rest is for Callgraph.
+    let mut cargo_args: Vec<String> = std::env::args().skip(2).collect();
+    let mut callgraph_args = match cargo_args.iter().position(|arg| arg == "--") {
+        Some(i) => cargo_args.split_off(i).split_off(1),
+        None => vec![],
+    };
+
+    // Callgraph's own flags are accepted before the `--` as well, but `cargo`
+    // must not see them.
+    let options = Options::from_args(&mut cargo_args)
+        .and_then(|mut options| {
+            options.merge(Options::from_args(&mut callgraph_args)?);
+            Ok(options)
+        })
+        .unwrap_or_else(|e| show_error(e));
+    let selection = PackageSelection::from_args(&mut cargo_args).unwrap_or_else(|e| show_error(e));
+    let target_selection = TargetSelection::from_args(&mut cargo_args);
+
     // Now run the command.
-    let package = cargo_package();
-    let mut targets: Vec<_> = package.targets.into_iter().collect();
+    let (packages, metadata) = cargo_packages(&selection);
+    let target_directory = metadata.target_directory.clone().into_std_path_buf();
+
+    // Every target writes its callgraph here, they are merged in the end.
+    let fragment_dir = target_directory.join("callgraph");
+    std::fs::create_dir_all(&fragment_dir).expect("could not create callgraph directory");
+    let mut graphs = vec![];
+
+    // The dependencies analyzed with `--extern-depth` or `--whole-program` write
+    // theirs to `<crate>-<hash>.json` when they are built for the first target.
+    // Clean them, so they are built.
+    let extern_depth = if options.whole_program { usize::MAX } else { options.extern_depth };
+    let extern_packages = extern_packages(&metadata, &packages, extern_depth, target_selection.uses_dev_dependencies());
+    for package in &extern_packages {
+        clean_package(&format!("{}@{}", package.name, package.version));
+    }
+    let extern_crates: Vec<String> = extern_packages.iter().filter_map(lib_crate_name).collect();
+    // A stale callgraph of a dependency that fails to build must not be linked.
+    for crate_name in &extern_crates {
+        for fragment in extern_fragments(&fragment_dir, crate_name) {
+            let _ = std::fs::remove_file(fragment);
+        }
+    }
 
-    // Ensure `lib` is compiled before `bin`
-    targets.sort_by_key(|target| TargetKind::from(target) as u8);
+    for package in packages {
+        let mut fragments = vec![];
+        let mut targets: Vec<_> = package.targets.into_iter().collect();
 
-    for target in targets {
-        // Skip `cargo callgraph`
-        let mut args = std::env::args().skip(2);
-        let kind = TargetKind::from(&target);
+        // Ensure `lib` is compiled before `bin`
+        targets.sort_by_key(|target| TargetKind::from(target) as u8);
 
-        println!("Target name: {}", &target.name);
+        // Clean the result to disable Cargo's freshness check
+        clean_package(&package.name);
 
-        // Now we run `cargo check $FLAGS $ARGS`, giving the user the
-        // change to add additional arguments. `FLAGS` is set to identify
-        // this target. The user gets to control what gets actually passed to Callgraph.
-        let mut cmd = Command::new("cargo");
-        cmd.arg("check");
+        for target in targets {
+            let kind = TargetKind::from(&target);
 
-        match kind {
-            TargetKind::Bin => {
-                // Analyze all the binaries.
-                cmd.arg("--bin").arg(&target.name);
-            }
-            TargetKind::Library => {
-                // There can be only one lib in a crate.
-                cmd.arg("--lib");
-                // Clean the result to disable Cargo's freshness check
-                clean_package(&package.name);
-            }
-            TargetKind::Unknown => {
-                println!(
-                    "Target {}:{} is not supported",
-                    target.kind.as_slice().join("/"),
-                    &target.name
-                );
-                continue;
-            }
-        }
+            eprintln!("Target name: {}", &target.name);
 
-        if !cfg!(debug_assertions) && !verbose {
-            cmd.arg("-q");
-        }
+            // With `--tests`, the lib and bins are also checked with `--test`, so
+            // that their unit tests are analyzed too. Their `main` and the code
+            // under `#[cfg(not(test))]` are only in the normal build.
+            let test_builds: &[bool] = if target_selection.tests && matches!(kind, TargetKind::Library | TargetKind::Bin) {
+                &[false, true]
+            } else {
+                &[false]
+            };
+            for &test_build in test_builds {
+                // Now we run `cargo check $FLAGS $ARGS`, giving the user the
+                // change to add additional arguments. `FLAGS` is set to identify
+                // this target. The user gets to control what gets actually passed to Callgraph.
+                let mut cmd = Command::new("cargo");
+                cmd.arg("check");
+                cmd.arg("-p").arg(&package.name);
+
+                match kind {
+                    TargetKind::Bin => {
+                        // Analyze all the binaries.
+                        cmd.arg("--bin").arg(&target.name);
+                    }
+                    TargetKind::Library => {
+                        // There can be only one lib in a crate.
+                        cmd.arg("--lib");
+                    }
+                    TargetKind::Unknown => {
+                        eprintln!(
+                            "Target {}:{} is not supported",
+                            target.kind.as_slice().join("/"),
+                            &target.name
+                        );
+                        continue;
+                    }
+                    _ if !target_selection.is_selected(&kind) => {
+                        eprintln!(
+                            "Target {}:{} is skipped, use --{}s or --all-targets to analyze it",
+                            kind, &target.name, kind
+                        );
+                        continue;
+                    }
+                    TargetKind::Example | TargetKind::Test | TargetKind::Bench => {
+                        cmd.arg(format!("--{}", kind)).arg(&target.name);
+                    }
+                }
 
-        // Forward user-defined `cargo` args until first `--`.
-        while let Some(arg) = args.next() {
-            if arg == "--" {
-                break;
-            }
-            cmd.arg(arg);
-        }
+                if test_build {
+                    cmd.arg("--profile").arg("test");
+                }
 
-        // We want to always run `cargo` with `--target`. This later helps us detect
-        // which crates are proc-macro/build-script (host crates) and which crates are
-        // needed for the program itself.
-        if get_arg_flag_value("--target").is_none() {
-            // When no `--target` is given, default to the host.
-            cmd.arg("--target");
-            cmd.arg(version_info().host);
-        }
+                if !cfg!(debug_assertions) && !verbose {
+                    cmd.arg("-q");
+                }
 
-        // Serialize the remaining args into a special environment variable.
-        // This will be read by `inside_cargo_rustc` when we go to invoke
-        // our actual target crate (the binary or the test we are running).
-        // Since we're using "cargo check", we have no other way of passing
-        // these arguments.
-        let args_vec: Vec<String> = args.collect();
-        cmd.env(
-            "CALLGRAPH_ARGS",
-            serde_json::to_string(&args_vec).expect("failed to serialize args"),
-        );
+                // Forward user-defined `cargo` args.
+                cmd.args(&cargo_args);
 
-        // Set `RUSTC_WRAPPER` to ourselves.  Cargo will prepend that binary to its usual invocation,
-        // i.e., the first argument is `rustc` -- which is what we use in `main` to distinguish
-        // the two codepaths.
-        if env::var_os("RUSTC_WRAPPER").is_some() {
-            eprintln!("WARNING: Ignoring existing `RUSTC_WRAPPER` environment variable, Callgraph does not support wrapping.");
-        }
+                // We want to always run `cargo` with `--target`. This later helps us detect
+                // which crates are proc-macro/build-script (host crates) and which crates are
+                // needed for the program itself.
+                if get_arg_flag_value("--target").is_none() {
+                    // When no `--target` is given, default to the host.
+                    cmd.arg("--target");
+                    cmd.arg(version_info().host);
+                }
 
-        let path = std::env::current_exe().expect("current executable path invalid");
-        cmd.env("RUSTC_WRAPPER", path);
-        if verbose {
-            cmd.env("CALLGRAPH_VERBOSE", ""); // this makes `inside_cargo_rustc` verbose.
-            eprintln!("+ {:?}", cmd);
-        }
+                let suffix = if test_build { "-test" } else { "" };
+                let fragment = fragment_dir.join(format!("{}-{}-{}{}.json", package.name, kind, target.name, suffix));
+                // A stale fragment must not end up in the result if the target fails to run.
+                let _ = std::fs::remove_file(&fragment);
+                let target_options = Options {
+                    output_format: OutputFormat::Json,
+                    output: Some(fragment.clone()),
+                    // impls of the other crates are only known after merging
+                    dynamic_calls: DynamicCalls::Decl,
+                    ..options.clone()
+                };
+
+                // Serialize the remaining args into a special environment variable.
+                // This will be read by `inside_cargo_rustc` when we go to invoke
+                // our actual target crate (the binary or the test we are running).
+                // Since we're using "cargo check", we have no other way of passing
+                // these arguments.
+                let mut args_vec = callgraph_args.clone();
+                args_vec.extend(target_options.to_args());
+                cmd.env(
+                    "CALLGRAPH_ARGS",
+                    serde_json::to_string(&args_vec).expect("failed to serialize args"),
+                );
+                // Path dependencies within the workspace are local crates too, only
+                // the crate of this target is analyzed.
+                cmd.env("CALLGRAPH_CRATE_NAME", target.name.replace('-', "_"));
+                // Except for the dependencies selected by `--extern-depth` or `--whole-program`.
+                cmd.env(
+                    "CALLGRAPH_EXTERN_CRATES",
+                    serde_json::to_string(&extern_crates).expect("failed to serialize crate names"),
+                );
+                cmd.env("CALLGRAPH_EXTERN_DIR", &fragment_dir);
 
-        let mut child = cmd.spawn().expect("could not run cargo check");
-        // 1 hour timeout
-        match child
-            .wait_timeout(Duration::from_secs(60 * 60))
-            .expect("failed to wait for subprocess")
-        {
-            Some(exit_status) => {
-                if !exit_status.success() {
-                    show_error("Finished with non-zero exit code");
+                // Set `RUSTC_WRAPPER` to ourselves.  Cargo will prepend that binary to its usual invocation,
+                // i.e., the first argument is `rustc` -- which is what we use in `main` to distinguish
+                // the two codepaths.
+                if env::var_os("RUSTC_WRAPPER").is_some() {
+                    eprintln!("WARNING: Ignoring existing `RUSTC_WRAPPER` environment variable, Callgraph does not support wrapping.");
                 }
+
+                let path = std::env::current_exe().expect("current executable path invalid");
+                cmd.env("RUSTC_WRAPPER", path);
+                if verbose {
+                    cmd.env("CALLGRAPH_VERBOSE", ""); // this makes `inside_cargo_rustc` verbose.
+                    eprintln!("+ {:?}", cmd);
+                }
+
+                let mut child = cmd.spawn().expect("could not run cargo check");
+                // 1 hour timeout
+                match child
+                    .wait_timeout(Duration::from_secs(60 * 60))
+                    .expect("failed to wait for subprocess")
+                {
+                    Some(exit_status) => {
+                        if !exit_status.success() {
+                            show_error("Finished with non-zero exit code");
+                        }
+                    }
+                    None => {
+                        child.kill().expect("failed to kill subprocess");
+                        child.wait().expect("failed to wait for subprocess");
+                        show_error("Killed due to timeout");
+                    }
+                };
+
+                fragments.push(fragment);
             }
-            None => {
-                child.kill().expect("failed to kill subprocess");
-                child.wait().expect("failed to wait for subprocess");
-                show_error("Killed due to timeout");
+        }
+
+        // Merge the per-target callgraphs of the package, this links calls
+        // from the bins into the lib.
+        let package_callgraph = link(fragments.iter().filter_map(|fragment| match Callgraph::read(fragment) {
+            Ok(fragment) => Some(fragment),
+            Err(e) => {
+                eprintln!("WARNING: could not read {}: {}", fragment.display(), e);
+                None
             }
+        }));
+        let package_options = Options {
+            output_format: OutputFormat::Json,
+            output: Some(fragment_dir.join(format!("{}.json", package.name))),
+            ..Default::default()
         };
+        if let Err(e) = output::write(&package_callgraph, &package_options) {
+            show_error(format!("failed to write callgraph of {}: {}", package.name, e));
+        }
+
+        graphs.push(package_callgraph);
+    }
+    // The newest callgraph of each analyzed dependency.
+    for crate_name in &extern_crates {
+        let Some(fragment) = newest_extern_fragment(&fragment_dir, crate_name) else {
+            eprintln!("WARNING: dependency {} was not analyzed", crate_name);
+            continue;
+        };
+        match Callgraph::read(&fragment) {
+            Ok(fragment) => graphs.push(fragment),
+            Err(e) => eprintln!("WARNING: could not read {}: {}", fragment.display(), e),
+        }
+    }
+    // Linking resolves the calls between the packages and into the dependencies.
+    let mut callgraph = link(graphs);
+    resolve_dynamic_calls(&mut callgraph, &options);
+    eprintln!("callgraph: {}", callgraph.summary());
+    if let Err(e) = output::write(&callgraph, &options) {
+        show_error(format!("failed to write callgraph: {}", e));
     }
 }
 
@@ -421,13 +747,41 @@ fn inside_cargo_rustc() {
         };
         let entry_path: &Path = entry_path_arg.as_ref();
 
-        entry_path.is_relative()
+        let is_selected_crate = match std::env::var("CALLGRAPH_CRATE_NAME") {
+            Ok(crate_name) => get_arg_flag_value("--crate-name").as_ref() == Some(&crate_name),
+            Err(_) => true,
+        };
+
+        entry_path.is_relative() && is_selected_crate
     }
 
     fn is_crate_type_lib() -> bool {
         any_arg_flag("--crate-type", TargetKind::is_lib_str)
     }
 
+    /// Returns whether we are building a dependency selected by `--extern-depth`
+    /// or `--whole-program`.
+    fn is_extern_crate() -> bool {
+        let Ok(crates) = std::env::var("CALLGRAPH_EXTERN_CRATES") else {
+            return false;
+        };
+        let crates: Vec<String> =
+            serde_json::from_str(&crates).expect("failed to deserialize CALLGRAPH_EXTERN_CRATES");
+        get_arg_flag_value("--crate-name").is_some_and(|crate_name| crates.contains(&crate_name))
+    }
+
+    /// The callgraph flags for a dependency: the flags of the target, but the
+    /// output goes to a file of its own.
+    fn extern_args(mut args: Vec<String>) -> Vec<String> {
+        let mut options = Options::from_args(&mut args).expect("invalid CALLGRAPH_ARGS");
+        let crate_name = get_arg_flag_value("--crate-name").expect("missing --crate-name");
+        // `-C metadata` tells apart the builds of a crate, e.g. with different features
+        let hash = get_codegen_option("metadata").unwrap_or_default();
+        let dir = std::env::var("CALLGRAPH_EXTERN_DIR").expect("missing CALLGRAPH_EXTERN_DIR");
+        options.output = Some(Path::new(&dir).join(format!("{}-{}.json", crate_name, hash)));
+        options.to_args()
+    }
+
     fn run_command(mut cmd: Command) {
         // Run it.
         let verbose = std::env::var_os("CALLGRAPH_VERBOSE").is_some();
@@ -448,7 +802,7 @@ fn inside_cargo_rustc() {
     // TODO: Miri sets custom sysroot here, check if it is needed for us (CALLGRAPH-30)
 
     let is_direct_target = contains_target_flag() && is_target_crate();
-    let mut is_additional_target = false;
+    let is_additional_target = !is_direct_target && contains_target_flag() && is_extern_crate();
 
     if is_direct_target || is_additional_target {
         let mut cmd = Command::new(find_callgraph());
@@ -461,8 +815,11 @@ fn inside_cargo_rustc() {
         //
         // `env::var` is okay here, well-formed JSON is always UTF-8.
         let magic = std::env::var("CALLGRAPH_ARGS").expect("missing CALLGRAPH_ARGS");
-        let callgraph_args: Vec<String> =
+        let mut callgraph_args: Vec<String> =
             serde_json::from_str(&magic).expect("failed to deserialize CALLGRAPH_ARGS");
+        if is_additional_target {
+            callgraph_args = extern_args(callgraph_args);
+        }
         cmd.args(callgraph_args);
 
         run_command(cmd);
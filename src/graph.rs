@@ -0,0 +1,786 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Stable identifier of a node: the crate name followed by the def path,
+/// e.g. `example_code::fun_method::<impl example_code::fun_method::T for example_code::fun_method::S>::bla`.
+/// It is the same in every crate referring to the node and across runs.
+pub type NodeId = String;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    // free function
+    Function,
+    // associated function of an inherent impl
+    AssocFn,
+    // trait method declaration without default implementation
+    MethodDecl,
+    // trait method declaration with default implementation
+    MethodDefault,
+    // implementation of a trait method
+    MethodImpl,
+    // closure, linked to its enclosing function by a `Closure` edge
+    Closure,
+    // constructor of a tuple struct or tuple variant
+    Constructor,
+    // initializer of a const, or a const expression like an array length or
+    // an enum discriminant, the caller of the calls in it
+    ConstInit,
+    // initializer of a static
+    StaticInit,
+    // unknown callee of an indirect call, the path is the callee's type
+    Indirect,
+    // function of a crate that is not analyzed, e.g. std
+    External,
+    // call target of the analyzed crate whose definition was not visited
+    Unknown,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeKind {
+    // callee is known at compile time
+    Static,
+    // callee is a trait method decl, resolved at runtime
+    Dynamic,
+    // call through a closure, fn pointer or Fn trait object
+    Indirect,
+    // caller defines the callee closure
+    Closure,
+    // callee is an impl of a dynamically called trait method decl, added by
+    // class hierarchy analysis
+    Possible,
+    // callee is the impl of an overloaded operator, e.g. `Add::add` for `a + b`
+    Operator,
+    // call that is not in the source: `Drop::drop`, `Deref::deref` in autoderef
+    // and `From::from` in `?`, with `--implicit-calls`
+    Implicit,
+    // callee is the constructor of a tuple struct or tuple variant, e.g. `Some(x)`
+    Constructor,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryPoint {
+    // the `main` function of a binary
+    Main,
+    // a `#[test]` or `#[bench]` function
+    Test,
+}
+
+/// A source range, lines and columns are 1-based. The end column is the one
+/// after the last character.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+pub struct SourceSpan {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+/// A macro invocation that generated a node or call, with
+/// `--include-macro-expansions`.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct MacroExpansion {
+    // e.g. `println!`, `#[derive(Debug)]`
+    pub name: String,
+    // where the macro is invoked
+    pub call_site: SourceSpan,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Node {
+    pub id: NodeId,
+    pub kind: NodeKind,
+    // def path as printed by rustc, e.g. `fun_method::S::met`
+    pub path: String,
+    // crate the node is defined in
+    pub crate_name: String,
+    // the def path hash of rustc, the same in every crate referring to the
+    // node, null for indirect nodes
+    pub def_path_hash: Option<String>,
+    // definition site, for external nodes the head of the definition as
+    // recorded in the crate metadata
+    pub span: Option<SourceSpan>,
+    pub entry_point: Option<EntryPoint>,
+    // the macros the definition is expanded from, innermost first
+    pub expansion: Vec<MacroExpansion>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Edge {
+    // enclosing function or initializer of the call
+    pub caller: Option<NodeId>,
+    pub callee: NodeId,
+    pub kind: EdgeKind,
+    // the call site, or the place that triggers an implicit call
+    pub span: Option<SourceSpan>,
+    // definition sites of the caller and the callee, like `Node::span`
+    pub caller_span: Option<SourceSpan>,
+    pub callee_span: Option<SourceSpan>,
+    // the macros the call site is expanded from, innermost first
+    pub expansion: Vec<MacroExpansion>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnresolvedReason {
+    // typeck has no method for a method call
+    UnresolvedMethod,
+    // typeck has no type for the callee expression
+    UnknownCalleeType,
+    // the callee depends on generic parameters, or with the mir backend its
+    // instances recurse deeper than the recursion limit
+    TooGeneric,
+    // instance resolution failed
+    ResolveError,
+    // the callee resolved to something that is not a function
+    UnexpectedCallee,
+}
+
+impl Display for UnresolvedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                UnresolvedReason::UnresolvedMethod => "unresolved method",
+                UnresolvedReason::UnknownCalleeType => "unknown callee type",
+                UnresolvedReason::TooGeneric => "too generic",
+                UnresolvedReason::ResolveError => "resolve error",
+                UnresolvedReason::UnexpectedCallee => "unexpected callee",
+            }
+        )
+    }
+}
+
+/// A call site whose callee could not be determined.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct UnresolvedCall {
+    pub caller: Option<NodeId>,
+    // the called function before resolution, if known
+    pub callee: Option<NodeId>,
+    pub reason: UnresolvedReason,
+    // the call expression
+    pub span: SourceSpan,
+}
+
+/// Links a trait method declaration to one of its implementations.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct ImplLink {
+    pub decl: NodeId,
+    pub implementation: NodeId,
+    // self type of the impl, null for a default implementation. ADTs and
+    // closures are named by their node id, other types as printed by rustc.
+    pub self_ty: Option<String>,
+}
+
+/// A type that is coerced to a trait object, e.g. `S` in `&S` to `&dyn T`.
+/// Only such types can be the receiver of a dynamic call.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct DynCoercion {
+    // the function or initializer containing the coercion, null in another
+    // crate
+    pub function: Option<NodeId>,
+    // named like `ImplLink::self_ty`
+    pub ty: String,
+}
+
+/// The callgraph of one or more crates.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Callgraph {
+    // the analyzed crates
+    pub crates: Vec<String>,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    pub impls: Vec<ImplLink>,
+    pub unresolved: Vec<UnresolvedCall>,
+    pub dyn_coercions: Vec<DynCoercion>,
+}
+
+/// Counts of nodes, edges and unresolved calls, printed after the analysis.
+pub struct Summary {
+    pub nodes: usize,
+    pub edges: usize,
+    pub unresolved: BTreeMap<UnresolvedReason, usize>,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unresolved: usize = self.unresolved.values().sum();
+        write!(
+            f,
+            "{} nodes, {} edges, {} unresolved calls",
+            self.nodes, self.edges, unresolved
+        )?;
+        if unresolved > 0 {
+            let reasons: Vec<String> = self
+                .unresolved
+                .iter()
+                .map(|(reason, count)| format!("{}: {}", reason, count))
+                .collect();
+            write!(f, " ({})", reasons.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Callgraph {
+    pub fn new(crate_name: impl Into<String>) -> Callgraph {
+        Callgraph {
+            crates: vec![crate_name.into()],
+            ..Default::default()
+        }
+    }
+
+    /// Reads a callgraph written with `--output-format json`.
+    pub fn read(path: &Path) -> io::Result<Callgraph> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut unresolved = BTreeMap::new();
+        for call in &self.unresolved {
+            *unresolved.entry(call.reason).or_default() += 1;
+        }
+        Summary {
+            nodes: self.nodes.len(),
+            edges: self.edges.len(),
+            unresolved,
+        }
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// Returns all edges leaving `id`.
+    pub fn callees<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a Edge> {
+        self.edges
+            .iter()
+            .filter(move |e| e.caller.as_deref() == Some(id))
+    }
+
+    /// Returns the implementations linked to the method declaration `decl`.
+    pub fn impls_of<'a>(&'a self, decl: &'a str) -> impl Iterator<Item = &'a NodeId> {
+        self.impls
+            .iter()
+            .filter(move |l| l.decl == decl)
+            .map(|l| &l.implementation)
+    }
+
+    // A `Possible` edge from the caller of every dynamic call to each impl of
+    // the called method that is accepted by `keep`.
+    fn possible_edges(&self, keep: impl Fn(&ImplLink) -> bool) -> Vec<Edge> {
+        let spans: HashMap<&str, &SourceSpan> = self
+            .nodes
+            .iter()
+            .filter_map(|node| Some((node.id.as_str(), node.span.as_ref()?)))
+            .collect();
+        let mut possible = vec![];
+        for edge in self.edges.iter().filter(|e| e.kind == EdgeKind::Dynamic) {
+            // a default implementation is already the callee of the dynamic edge
+            let links = self.impls.iter().filter(|l| l.decl == edge.callee && l.implementation != edge.callee);
+            for link in links.filter(|l| keep(l)) {
+                possible.push(Edge {
+                    caller: edge.caller.clone(),
+                    callee: link.implementation.clone(),
+                    kind: EdgeKind::Possible,
+                    span: edge.span.clone(),
+                    caller_span: edge.caller_span.clone(),
+                    callee_span: spans.get(link.implementation.as_str()).map(|span| (*span).clone()),
+                    expansion: edge.expansion.clone(),
+                });
+            }
+        }
+        possible
+    }
+
+    // The nodes reachable from the entry points by `self.edges` and `extra`.
+    // Without entry points, e.g. in a lib, every node is reachable.
+    fn reachable<'a>(&'a self, extra: &'a [Edge]) -> HashSet<&'a str> {
+        let mut queue: VecDeque<&str> = self
+            .nodes
+            .iter()
+            .filter(|node| node.entry_point.is_some())
+            .map(|node| node.id.as_str())
+            .collect();
+        if queue.is_empty() {
+            return self.nodes.iter().map(|node| node.id.as_str()).collect();
+        }
+        let mut callees: HashMap<&str, Vec<&str>> = HashMap::new();
+        for edge in self.edges.iter().chain(extra) {
+            if let Some(caller) = &edge.caller {
+                callees.entry(caller.as_str()).or_default().push(edge.callee.as_str());
+            }
+        }
+        let mut reachable = HashSet::new();
+        while let Some(id) = queue.pop_front() {
+            if reachable.insert(id) {
+                queue.extend(callees.get(id).into_iter().flatten());
+            }
+        }
+        reachable
+    }
+
+    /// Class hierarchy analysis: adds a `Possible` edge from the caller of
+    /// every dynamic call to each known implementation of the called method.
+    /// Impls of other crates are only known once their graphs are merged.
+    pub fn expand_dynamic_calls(&mut self) {
+        let possible = self.possible_edges(|_| true);
+        self.edges.extend(possible);
+        self.sort();
+    }
+
+    /// Rapid type analysis: like `expand_dynamic_calls`, but only adds edges
+    /// to impls whose self type is coerced to a trait object in a function
+    /// that is reachable from the entry points, including through the added
+    /// edges.
+    pub fn expand_dynamic_calls_rta(&mut self) {
+        let mut possible: Vec<Edge> = vec![];
+        loop {
+            let reachable = self.reachable(&possible);
+            let live: HashSet<&str> = self
+                .dyn_coercions
+                .iter()
+                .filter(|c| c.function.as_deref().map_or(true, |f| reachable.contains(f)))
+                .map(|c| c.ty.as_str())
+                .collect();
+            let next = self.possible_edges(|link| link.self_ty.as_deref().is_some_and(|ty| live.contains(ty)));
+            // more live types only ever add edges
+            if next.len() == possible.len() {
+                break;
+            }
+            possible = next;
+        }
+        self.edges.extend(possible);
+        self.sort();
+    }
+
+    /// Sorts nodes, edges and impls by id and unresolved calls by location, so
+    /// that the output of two runs can be diffed.
+    pub fn sort(&mut self) {
+        self.nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        self.edges.sort_by(|a, b| {
+            (&a.caller, &a.callee, a.kind, &a.span).cmp(&(&b.caller, &b.callee, b.kind, &b.span))
+        });
+        self.impls
+            .sort_by(|a, b| (&a.decl, &a.implementation).cmp(&(&b.decl, &b.implementation)));
+        self.unresolved.sort_by(|a, b| {
+            let a = (&a.span.file, a.span.line, a.span.column);
+            let b = (&b.span.file, b.span.line, b.span.column);
+            a.cmp(&b)
+        });
+        self.dyn_coercions
+            .sort_by(|a, b| (&a.function, &a.ty).cmp(&(&b.function, &b.ty)));
+    }
+
+    /// Adds the nodes and edges of `other` to `self`. Nodes with the same id
+    /// are merged, so a call into another crate is linked to the callee's
+    /// definition once both crates are part of the graph. Merging does not
+    /// sort, see `link` to merge many graphs.
+    pub fn merge(&mut self, other: Callgraph) {
+        self.append(other);
+        self.dedup();
+    }
+
+    fn append(&mut self, other: Callgraph) {
+        self.crates.extend(other.crates);
+        self.nodes.extend(other.nodes);
+        self.edges.extend(other.edges);
+        self.impls.extend(other.impls);
+        self.unresolved.extend(other.unresolved);
+        self.dyn_coercions.extend(other.dyn_coercions);
+    }
+
+    // Removes what appears in more than one of the appended graphs, they may
+    // overlap, e.g. a lib and its test build.
+    fn dedup(&mut self) {
+        let mut by_id: HashMap<NodeId, usize> = HashMap::new();
+        let mut nodes: Vec<Node> = Vec::with_capacity(self.nodes.len());
+        for node in std::mem::take(&mut self.nodes) {
+            match by_id.get(&node.id) {
+                Some(&i) => {
+                    // prefer the crate that defines the node over the ones
+                    // that only call it
+                    if is_placeholder(nodes[i].kind) && !is_placeholder(node.kind) {
+                        nodes[i] = node;
+                    } else if nodes[i].entry_point.is_none() {
+                        // e.g. `main` is no entry point in a test build
+                        nodes[i].entry_point = node.entry_point;
+                    }
+                }
+                None => {
+                    by_id.insert(node.id.clone(), nodes.len());
+                    nodes.push(node);
+                }
+            }
+        }
+        self.nodes = nodes;
+        dedup_by_key(&mut self.edges, edge_key);
+        dedup_by_key(&mut self.unresolved, unresolved_key);
+        dedup_by_key(&mut self.impls, ImplLink::clone);
+        dedup_by_key(&mut self.dyn_coercions, DynCoercion::clone);
+        dedup_by_key(&mut self.crates, String::clone);
+    }
+
+    /// Replaces external and unknown nodes by the node with the same def path
+    /// hash that is defined in the graph, for when their ids differ. All
+    /// references to the replaced nodes are redirected to the definition.
+    /// Does not sort, like `merge`.
+    pub fn link_def_path_hashes(&mut self) {
+        let definitions: HashMap<&str, &Node> = self
+            .nodes
+            .iter()
+            .filter(|node| !is_placeholder(node.kind))
+            .filter_map(|node| Some((node.def_path_hash.as_deref()?, node)))
+            .collect();
+        let renamed: HashMap<NodeId, (NodeId, Option<SourceSpan>)> = self
+            .nodes
+            .iter()
+            .filter(|node| is_placeholder(node.kind))
+            .filter_map(|node| {
+                let definition = definitions.get(node.def_path_hash.as_deref()?)?;
+                (definition.id != node.id)
+                    .then(|| (node.id.clone(), (definition.id.clone(), definition.span.clone())))
+            })
+            .collect();
+        if renamed.is_empty() {
+            return;
+        }
+
+        let rename = |id: &mut NodeId| {
+            if let Some((definition, _)) = renamed.get(id) {
+                *id = definition.clone();
+            }
+        };
+        self.nodes.retain(|node| !renamed.contains_key(&node.id));
+        for edge in &mut self.edges {
+            if let Some(caller) = &mut edge.caller {
+                rename(caller);
+            }
+            // the span of an external node is only the head of the definition
+            if let Some((definition, span)) = renamed.get(&edge.callee) {
+                edge.callee = definition.clone();
+                edge.callee_span = span.clone();
+            }
+        }
+        for call in &mut self.unresolved {
+            call.caller.iter_mut().chain(&mut call.callee).for_each(rename);
+        }
+        for link in &mut self.impls {
+            rename(&mut link.decl);
+            rename(&mut link.implementation);
+        }
+        for coercion in &mut self.dyn_coercions {
+            coercion.function.iter_mut().for_each(rename);
+        }
+        dedup_by_key(&mut self.impls, ImplLink::clone);
+        dedup_by_key(&mut self.dyn_coercions, DynCoercion::clone);
+    }
+}
+
+// Edges are the same if they are the same call.
+fn edge_key(edge: &Edge) -> (Option<NodeId>, NodeId, EdgeKind, Option<SourceSpan>) {
+    (edge.caller.clone(), edge.callee.clone(), edge.kind, edge.span.clone())
+}
+
+fn unresolved_key(call: &UnresolvedCall) -> (Option<NodeId>, SourceSpan) {
+    (call.caller.clone(), call.span.clone())
+}
+
+// Keeps the first of the items with the same key.
+fn dedup_by_key<T, K: Hash + Eq>(items: &mut Vec<T>, key: impl Fn(&T) -> K) {
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(key(item)));
+}
+
+/// External and unknown nodes stand in for a definition that is not part of
+/// the graph.
+fn is_placeholder(kind: NodeKind) -> bool {
+    matches!(kind, NodeKind::External | NodeKind::Unknown)
+}
+
+/// Links the callgraphs of several crates, e.g. the fragments written by
+/// `cargo callgraph`, into one graph of the whole program. Calls into another
+/// crate are linked to the callee's definition by id or def path hash.
+pub fn link(fragments: impl IntoIterator<Item = Callgraph>) -> Callgraph {
+    let mut callgraph = Callgraph::default();
+    for fragment in fragments {
+        callgraph.append(fragment);
+    }
+    callgraph.dedup();
+    callgraph.link_def_path_hashes();
+    callgraph.sort();
+    callgraph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize) -> SourceSpan {
+        SourceSpan {
+            file: "src/lib.rs".to_string(),
+            line,
+            column: 1,
+            end_line: line,
+            end_column: 10,
+        }
+    }
+
+    fn node(id: &str, kind: NodeKind, def_path_hash: &str, line: usize) -> Node {
+        Node {
+            id: id.to_string(),
+            kind,
+            path: id.to_string(),
+            crate_name: id.split("::").next().unwrap().to_string(),
+            def_path_hash: Some(def_path_hash.to_string()),
+            span: Some(span(line)),
+            entry_point: None,
+            expansion: vec![],
+        }
+    }
+
+    fn edge(caller: &str, callee: &str, kind: EdgeKind, line: usize) -> Edge {
+        Edge {
+            caller: Some(caller.to_string()),
+            callee: callee.to_string(),
+            kind,
+            span: Some(span(line)),
+            caller_span: None,
+            callee_span: None,
+            expansion: vec![],
+        }
+    }
+
+    fn unresolved(caller: &str, callee: &str, line: usize) -> UnresolvedCall {
+        UnresolvedCall {
+            caller: Some(caller.to_string()),
+            callee: Some(callee.to_string()),
+            reason: UnresolvedReason::TooGeneric,
+            span: span(line),
+        }
+    }
+
+    fn ids(callgraph: &Callgraph) -> Vec<(&str, NodeKind)> {
+        callgraph.nodes.iter().map(|node| (node.id.as_str(), node.kind)).collect()
+    }
+
+    fn impl_link(decl: &str, implementation: &str, self_ty: &str) -> ImplLink {
+        ImplLink {
+            decl: decl.to_string(),
+            implementation: implementation.to_string(),
+            self_ty: Some(self_ty.to_string()),
+        }
+    }
+
+    fn coercion(function: Option<&str>, ty: &str) -> DynCoercion {
+        DynCoercion {
+            function: function.map(String::from),
+            ty: ty.to_string(),
+        }
+    }
+
+    fn possible_callees(callgraph: &Callgraph) -> Vec<&str> {
+        callgraph
+            .edges
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Possible)
+            .map(|e| e.callee.as_str())
+            .collect()
+    }
+
+    // `run` calls `T::m` dynamically. `S` is coerced in `main`, `U` only in
+    // the unreachable `dead`, `V` in `S::m`, which is only reachable through
+    // the possible edge to it, and `W` in another crate.
+    fn dynamic_call() -> Callgraph {
+        let mut callgraph = Callgraph::new("app");
+        let mut main = node("app::main", NodeKind::Function, "01", 1);
+        main.entry_point = Some(EntryPoint::Main);
+        callgraph.nodes.push(main);
+        callgraph.nodes.push(node("app::run", NodeKind::Function, "02", 2));
+        callgraph.nodes.push(node("app::dead", NodeKind::Function, "03", 3));
+        callgraph.nodes.push(node("app::T::m", NodeKind::MethodDecl, "04", 4));
+        for (i, ty) in ["S", "U", "V", "W"].into_iter().enumerate() {
+            let implementation = format!("app::{}::m", ty);
+            callgraph.nodes.push(node(&implementation, NodeKind::MethodImpl, &format!("1{}", i), 10 + i));
+            callgraph.impls.push(impl_link("app::T::m", &implementation, &format!("app::{}", ty)));
+        }
+        callgraph.edges.push(edge("app::main", "app::run", EdgeKind::Static, 1));
+        callgraph.edges.push(edge("app::run", "app::T::m", EdgeKind::Dynamic, 2));
+        callgraph.dyn_coercions.push(coercion(Some("app::main"), "app::S"));
+        callgraph.dyn_coercions.push(coercion(Some("app::dead"), "app::U"));
+        callgraph.dyn_coercions.push(coercion(Some("app::S::m"), "app::V"));
+        callgraph.dyn_coercions.push(coercion(None, "app::W"));
+        callgraph
+    }
+
+    #[test]
+    fn expand_dynamic_calls_adds_all_impls() {
+        let mut callgraph = dynamic_call();
+        callgraph.expand_dynamic_calls();
+        assert_eq!(possible_callees(&callgraph), ["app::S::m", "app::U::m", "app::V::m", "app::W::m"]);
+    }
+
+    #[test]
+    fn expand_dynamic_calls_rta_adds_impls_of_live_types() {
+        let mut callgraph = dynamic_call();
+        callgraph.expand_dynamic_calls_rta();
+        // `V` becomes live through the edge to `S::m`, `U` never does
+        assert_eq!(possible_callees(&callgraph), ["app::S::m", "app::V::m", "app::W::m"]);
+        let possible = callgraph.edges.iter().find(|e| e.kind == EdgeKind::Possible).unwrap();
+        assert_eq!(possible.caller.as_deref(), Some("app::run"));
+        assert_eq!(possible.span, Some(span(2)));
+        assert_eq!(possible.callee_span, Some(span(10)));
+    }
+
+    #[test]
+    fn expand_dynamic_calls_rta_without_entry_points() {
+        // in a lib every function is reachable
+        let mut callgraph = dynamic_call();
+        callgraph.nodes[0].entry_point = None;
+        callgraph.expand_dynamic_calls_rta();
+        assert_eq!(possible_callees(&callgraph), ["app::S::m", "app::U::m", "app::V::m", "app::W::m"]);
+    }
+
+    #[test]
+    fn link_prefers_the_definition() {
+        let mut app = Callgraph::new("app");
+        app.nodes.push(node("app::main", NodeKind::Function, "01", 1));
+        app.nodes.push(node("dep::f", NodeKind::External, "02", 1));
+        app.edges.push(edge("app::main", "dep::f", EdgeKind::Static, 2));
+        let mut dep = Callgraph::new("dep");
+        dep.nodes.push(node("dep::f", NodeKind::Function, "02", 5));
+
+        // the order of the fragments does not matter
+        for fragments in [vec![app.clone(), dep.clone()], vec![dep, app]] {
+            let linked = link(fragments);
+            assert_eq!(ids(&linked), [("app::main", NodeKind::Function), ("dep::f", NodeKind::Function)]);
+            assert_eq!(linked.node("dep::f").unwrap().span, Some(span(5)));
+            assert_eq!(linked.crates.len(), 2);
+            assert_eq!(linked.edges.len(), 1);
+        }
+    }
+
+    #[test]
+    fn link_keeps_the_entry_point_of_a_duplicate() {
+        // `main` is no entry point in the test build of a bin
+        let test_build = {
+            let mut callgraph = Callgraph::new("app");
+            callgraph.nodes.push(node("app::main", NodeKind::Function, "01", 1));
+            callgraph
+        };
+        let mut build = test_build.clone();
+        build.nodes[0].entry_point = Some(EntryPoint::Main);
+
+        let linked = link([test_build, build]);
+        assert_eq!(linked.nodes.len(), 1);
+        assert_eq!(linked.nodes[0].entry_point, Some(EntryPoint::Main));
+    }
+
+    #[test]
+    fn link_dedups_overlapping_fragments() {
+        let mut lib = Callgraph::new("app");
+        lib.nodes.push(node("app::f", NodeKind::Function, "01", 1));
+        lib.nodes.push(node("app::g", NodeKind::Function, "02", 5));
+        lib.edges.push(edge("app::f", "app::g", EdgeKind::Static, 2));
+        lib.unresolved.push(unresolved("app::f", "app::h", 3));
+        lib.dyn_coercions.push(DynCoercion {
+            function: Some("app::f".to_string()),
+            ty: "app::S".to_string(),
+        });
+        // the test build of the lib has the same calls and one more
+        let mut test_build = lib.clone();
+        test_build.edges.push(edge("app::f", "app::g", EdgeKind::Static, 4));
+
+        let linked = link([lib, test_build]);
+        assert_eq!(linked.crates, ["app"]);
+        assert_eq!(linked.nodes.len(), 2);
+        let lines: Vec<usize> = linked.edges.iter().map(|e| e.span.as_ref().unwrap().line).collect();
+        assert_eq!(lines, [2, 4]);
+        assert_eq!(linked.unresolved.len(), 1);
+        assert_eq!(linked.dyn_coercions.len(), 1);
+    }
+
+    #[test]
+    fn link_def_path_hashes_redirects_to_the_definition() {
+        // the dependency is known under another name in `app`, e.g. after a
+        // rename in `Cargo.toml`
+        let mut app = Callgraph::new("app");
+        app.nodes.push(node("app::main", NodeKind::Function, "01", 1));
+        app.nodes.push(node("renamed::f", NodeKind::External, "02", 1));
+        app.nodes.push(node("renamed::T::m", NodeKind::External, "03", 1));
+        app.nodes.push(node("renamed::g", NodeKind::External, "04", 1));
+        app.edges.push(edge("app::main", "renamed::f", EdgeKind::Static, 2));
+        app.edges.push(edge("renamed::f", "renamed::g", EdgeKind::Static, 3));
+        app.unresolved.push(unresolved("renamed::f", "renamed::g", 4));
+        app.impls.push(ImplLink {
+            decl: "renamed::T::m".to_string(),
+            implementation: "app::main".to_string(),
+            self_ty: None,
+        });
+        app.dyn_coercions.push(DynCoercion {
+            function: Some("renamed::f".to_string()),
+            ty: "app::S".to_string(),
+        });
+        let mut dep = Callgraph::new("dep");
+        dep.nodes.push(node("dep::f", NodeKind::Function, "02", 10));
+        dep.nodes.push(node("dep::T::m", NodeKind::MethodDecl, "03", 20));
+        dep.impls.push(ImplLink {
+            decl: "dep::T::m".to_string(),
+            implementation: "app::main".to_string(),
+            self_ty: None,
+        });
+        dep.dyn_coercions.push(DynCoercion {
+            function: Some("dep::f".to_string()),
+            ty: "app::S".to_string(),
+        });
+
+        let linked = link([app, dep]);
+        // `renamed::g` has no definition in the graph
+        assert_eq!(
+            ids(&linked),
+            [
+                ("app::main", NodeKind::Function),
+                ("dep::T::m", NodeKind::MethodDecl),
+                ("dep::f", NodeKind::Function),
+                ("renamed::g", NodeKind::External),
+            ]
+        );
+        let calls: Vec<(&str, &str)> = linked
+            .edges
+            .iter()
+            .map(|e| (e.caller.as_deref().unwrap(), e.callee.as_str()))
+            .collect();
+        assert_eq!(calls, [("app::main", "dep::f"), ("dep::f", "renamed::g")]);
+        // the callee span is the definition, not the head of the external node
+        assert_eq!(linked.edges[0].callee_span, Some(span(10)));
+        assert_eq!(linked.unresolved[0].caller.as_deref(), Some("dep::f"));
+        assert_eq!(linked.unresolved[0].callee.as_deref(), Some("renamed::g"));
+        let impls: Vec<(&str, &str)> =
+            linked.impls.iter().map(|l| (l.decl.as_str(), l.implementation.as_str())).collect();
+        assert_eq!(impls, [("dep::T::m", "app::main")]);
+        let coercions: Vec<Option<&str>> = linked.dyn_coercions.iter().map(|c| c.function.as_deref()).collect();
+        assert_eq!(coercions, [Some("dep::f")]);
+    }
+
+    #[test]
+    fn link_def_path_hashes_keeps_nodes_with_the_same_id() {
+        let mut callgraph = Callgraph::new("app");
+        callgraph.nodes.push(node("app::f", NodeKind::Function, "01", 1));
+        callgraph.nodes.push(node("app::g", NodeKind::Unknown, "02", 1));
+        callgraph.edges.push(edge("app::f", "app::g", EdgeKind::Static, 2));
+        let before = callgraph.clone();
+        callgraph.link_def_path_hashes();
+        assert_eq!(callgraph, before);
+    }
+}
@@ -4,14 +4,21 @@ extern crate rustc_driver;
 extern crate rustc_interface;
 extern crate rustc_hir;
 extern crate rustc_middle;
+extern crate rustc_session;
 extern crate rustc_span;
 extern crate rustc_version;
 extern crate cargo_metadata;
 
 use rustc_middle::ty::TyCtxt;
 
+pub mod graph;
+pub mod options;
+pub mod output;
 mod visitor;
 
+pub use graph::{link, Callgraph};
+pub use options::{Backend, DynamicCalls, Options, OutputFormat};
+
 /// Returns the "default sysroot" that Callgraph will use if no `--sysroot` flag is set.
 /// Should be a compile-time constant.
 pub fn compile_time_sysroot() -> Option<String> {
@@ -30,15 +37,30 @@ pub fn compile_time_sysroot() -> Option<String> {
     let toolchain = option_env!("RUSTUP_TOOLCHAIN").or(option_env!("MULTIRUST_TOOLCHAIN"));
     Some(match (home, toolchain) {
         (Some(home), Some(toolchain)) => format!("{}/toolchains/{}", home, toolchain),
-        _ => option_env!("RUST_SYSROOT")
-            .expect("To build Callgraph without rustup, set the `RUST_SYSROOT` env var at build time")
-            .to_owned(),
+        _ => match option_env!("RUST_SYSROOT") {
+            Some(sysroot) => sysroot.to_owned(),
+            None => panic!("To build Callgraph without rustup, set the `RUST_SYSROOT` env var at build time"),
+        },
     })
 }
 
-pub fn analyze(&tcx: &TyCtxt<'_>) {
-    let mut visitor = visitor::CallgraphVisitor::new(&tcx);
+/// Adds the possible targets of dynamic calls as selected by `--dynamic-calls`.
+/// Should be applied to the merged callgraph, so impls in all crates are known.
+pub fn resolve_dynamic_calls(callgraph: &mut Callgraph, options: &Options) {
+    match options.dynamic_calls {
+        DynamicCalls::Decl => {}
+        DynamicCalls::Cha => callgraph.expand_dynamic_calls(),
+        DynamicCalls::Rta => callgraph.expand_dynamic_calls_rta(),
+    }
+}
+
+/// Computes the callgraph of the crate being compiled.
+pub fn analyze(&tcx: &TyCtxt<'_>, options: &Options) -> Callgraph {
+    let mut visitor = visitor::CallgraphVisitor::new(&tcx, options);
     tcx.hir().visit_all_item_likes_in_crate(&mut visitor);
-    
-    visitor.dump();
+    if options.backend == Backend::Mir {
+        visitor.collect_mir_calls();
+    }
+
+    visitor.into_callgraph()
 }
@@ -0,0 +1,264 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const OUTPUT_FORMAT_FLAG: &str = "--output-format";
+pub const OUTPUT_FLAG: &str = "--output";
+pub const BACKEND_FLAG: &str = "--backend";
+pub const DYNAMIC_CALLS_FLAG: &str = "--dynamic-calls";
+pub const IMPLICIT_CALLS_FLAG: &str = "--implicit-calls";
+pub const INCLUDE_MACRO_EXPANSIONS_FLAG: &str = "--include-macro-expansions";
+pub const EXTERN_DEPTH_FLAG: &str = "--extern-depth";
+pub const WHOLE_PROGRAM_FLAG: &str = "--whole-program";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OutputFormat {
+    /// Rust debug formatting of the `Callgraph`
+    #[default]
+    Text,
+    /// The serialized `Callgraph`, see the Readme for the schema
+    Json,
+    /// Graphviz DOT
+    Dot,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "dot" => Ok(OutputFormat::Dot),
+            _ => Err(format!("unknown output format `{}`, expected one of: text, json, dot", s)),
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                OutputFormat::Text => "text",
+                OutputFormat::Json => "json",
+                OutputFormat::Dot => "dot",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Backend {
+    /// Walks the HIR of every function, calls are resolved in the caller's context
+    #[default]
+    Hir,
+    /// Walks the MIR of the monomorphized instances reachable from the entry points
+    Mir,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hir" => Ok(Backend::Hir),
+            "mir" => Ok(Backend::Mir),
+            _ => Err(format!("unknown backend `{}`, expected one of: hir, mir", s)),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Backend::Hir => "hir",
+                Backend::Mir => "mir",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DynamicCalls {
+    /// A dynamic call leads to the trait method declaration only
+    #[default]
+    Decl,
+    /// Class hierarchy analysis, a dynamic call also leads to every known
+    /// implementation of the method
+    Cha,
+    /// Rapid type analysis, like `Cha` but only impls whose self type is
+    /// coerced to a trait object in a reachable function
+    Rta,
+}
+
+impl FromStr for DynamicCalls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decl" => Ok(DynamicCalls::Decl),
+            "cha" => Ok(DynamicCalls::Cha),
+            "rta" => Ok(DynamicCalls::Rta),
+            _ => Err(format!("unknown dynamic call resolution `{}`, expected one of: decl, cha, rta", s)),
+        }
+    }
+}
+
+impl Display for DynamicCalls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DynamicCalls::Decl => "decl",
+                DynamicCalls::Cha => "cha",
+                DynamicCalls::Rta => "rta",
+            }
+        )
+    }
+}
+
+/// The flags understood by `callgraph` itself, as opposed to the ones that are
+/// passed on to rustc.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Options {
+    pub output_format: OutputFormat,
+    // write to this file instead of stdout
+    pub output: Option<PathBuf>,
+    pub backend: Backend,
+    pub dynamic_calls: DynamicCalls,
+    // record calls that are not in the source, e.g. of `Drop::drop`
+    pub implicit_calls: bool,
+    // analyze the code generated by macros, too
+    pub include_macro_expansions: bool,
+    // how many levels of dependencies `cargo callgraph` analyzes, 0 keeps
+    // their functions as external nodes
+    pub extern_depth: usize,
+    // analyze every crate of the build, like an unlimited `extern_depth`
+    pub whole_program: bool,
+}
+
+impl Options {
+    /// Removes all callgraph flags from `args` and parses them.
+    pub fn from_args(args: &mut Vec<String>) -> Result<Options, String> {
+        let mut options = Options::default();
+        if let Some(format) = take_flag_value(args, OUTPUT_FORMAT_FLAG)? {
+            options.output_format = format.parse()?;
+        }
+        options.output = take_flag_value(args, OUTPUT_FLAG)?.map(PathBuf::from);
+        if let Some(backend) = take_flag_value(args, BACKEND_FLAG)? {
+            options.backend = backend.parse()?;
+        }
+        if let Some(dynamic_calls) = take_flag_value(args, DYNAMIC_CALLS_FLAG)? {
+            options.dynamic_calls = dynamic_calls.parse()?;
+        }
+        options.implicit_calls = take_flag(args, IMPLICIT_CALLS_FLAG);
+        options.include_macro_expansions = take_flag(args, INCLUDE_MACRO_EXPANSIONS_FLAG);
+        if let Some(extern_depth) = take_flag_value(args, EXTERN_DEPTH_FLAG)? {
+            options.extern_depth = extern_depth
+                .parse()
+                .map_err(|_| format!("invalid extern depth `{}`, expected a number", extern_depth))?;
+        }
+        options.whole_program = take_flag(args, WHOLE_PROGRAM_FLAG);
+        Ok(options)
+    }
+
+    /// Overrides `self` with the flags that were explicitly given in `other`.
+    pub fn merge(&mut self, other: Options) {
+        if other.output_format != OutputFormat::default() {
+            self.output_format = other.output_format;
+        }
+        if other.output.is_some() {
+            self.output = other.output;
+        }
+        if other.backend != Backend::default() {
+            self.backend = other.backend;
+        }
+        if other.dynamic_calls != DynamicCalls::default() {
+            self.dynamic_calls = other.dynamic_calls;
+        }
+        self.implicit_calls |= other.implicit_calls;
+        self.include_macro_expansions |= other.include_macro_expansions;
+        if other.extern_depth != 0 {
+            self.extern_depth = other.extern_depth;
+        }
+        self.whole_program |= other.whole_program;
+    }
+
+    /// Turns the options back into flags, such that `from_args` parses them again.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            OUTPUT_FORMAT_FLAG.to_owned(),
+            self.output_format.to_string(),
+            BACKEND_FLAG.to_owned(),
+            self.backend.to_string(),
+            DYNAMIC_CALLS_FLAG.to_owned(),
+            self.dynamic_calls.to_string(),
+            EXTERN_DEPTH_FLAG.to_owned(),
+            self.extern_depth.to_string(),
+        ];
+        if self.implicit_calls {
+            args.push(IMPLICIT_CALLS_FLAG.to_owned());
+        }
+        if self.include_macro_expansions {
+            args.push(INCLUDE_MACRO_EXPANSIONS_FLAG.to_owned());
+        }
+        if self.whole_program {
+            args.push(WHOLE_PROGRAM_FLAG.to_owned());
+        }
+        if let Some(output) = &self.output {
+            args.push(OUTPUT_FLAG.to_owned());
+            args.push(output.display().to_string());
+        }
+        args
+    }
+}
+
+/// Removes the last occurrence of `--name value` or `--name=value` from `args`
+/// and returns the value. Earlier occurrences are removed as well.
+pub fn take_flag_value(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    Ok(take_flag_values(args, name)?.pop())
+}
+
+/// Removes all occurrences of `--name value` or `--name=value` from `args`
+/// and returns the values. Stops at `--`.
+pub fn take_flag_values(args: &mut Vec<String>, name: &str) -> Result<Vec<String>, String> {
+    let mut values = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--" {
+            break;
+        }
+        if args[i] == name {
+            if i + 1 >= args.len() {
+                return Err(format!("missing value for `{}`", name));
+            }
+            values.push(args.remove(i + 1));
+            args.remove(i);
+        } else if let Some(v) = args[i].strip_prefix(name).and_then(|s| s.strip_prefix('=')) {
+            values.push(v.to_owned());
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    Ok(values)
+}
+
+/// Removes all occurrences of the flag `name` from `args` and returns whether
+/// there were any. Stops at `--`.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    let before = args.len();
+    let mut i = 0;
+    args.retain(|arg| {
+        i += 1;
+        i > end || arg != name
+    });
+    args.len() != before
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::graph::{Callgraph, EdgeKind, NodeKind};
+use crate::options::{OutputFormat, Options};
+
+/// Renders `graph` in the given format.
+pub fn render(graph: &Callgraph, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Text => format!("{:#?}\n", graph),
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string_pretty(graph).expect("failed to serialize callgraph");
+            json.push('\n');
+            json
+        }
+        OutputFormat::Dot => dot(graph),
+    }
+}
+
+fn node_shape(kind: NodeKind) -> &'static str {
+    match kind {
+        NodeKind::Function | NodeKind::AssocFn => "box",
+        NodeKind::MethodDecl => "diamond",
+        NodeKind::MethodDefault => "octagon",
+        NodeKind::MethodImpl => "ellipse",
+        NodeKind::Closure => "hexagon",
+        NodeKind::Constructor => "cds",
+        NodeKind::ConstInit => "parallelogram",
+        NodeKind::StaticInit => "trapezium",
+        NodeKind::Indirect => "note",
+        NodeKind::External => "plain",
+        NodeKind::Unknown => "plaintext",
+    }
+}
+
+fn edge_attrs(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::Static => "style=solid",
+        EdgeKind::Dynamic => "style=dashed",
+        EdgeKind::Indirect => "style=bold",
+        EdgeKind::Closure => "style=tapered",
+        EdgeKind::Possible => "style=dotted",
+        EdgeKind::Operator => "style=solid, arrowhead=dot",
+        EdgeKind::Implicit => "style=solid, color=gray",
+        EdgeKind::Constructor => "style=solid, arrowhead=odot",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders `graph` as a Graphviz digraph. Node shapes encode the node kind,
+/// static calls are solid, operator calls end in a dot, constructor calls in
+/// an empty dot, implicit calls are gray, dynamic calls dashed, indirect calls
+/// bold, and dotted edges with an empty arrowhead lead from trait method
+/// declarations to their implementations.
+pub fn dot(graph: &Callgraph) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("digraph \"{}\" {{\n", escape(&graph.crates.join(", "))));
+    for node in &graph.nodes {
+        let mut label = escape(&node.path);
+        // derived methods are labeled with their derive, e.g. `#[derive(Clone)]`
+        if let Some(derive) = node.expansion.first().filter(|expn| expn.name.starts_with("#[derive(")) {
+            label.push_str(&format!("\\n{}", escape(&derive.name)));
+        }
+        out.push_str(&format!(
+            "    \"{}\" [label=\"{}\", shape={}];\n",
+            escape(&node.id),
+            label,
+            node_shape(node.kind)
+        ));
+    }
+    for edge in &graph.edges {
+        // calls outside of any function have no node to start from
+        if let Some(caller) = &edge.caller {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [{}];\n",
+                escape(caller),
+                escape(&edge.callee),
+                edge_attrs(edge.kind)
+            ));
+        }
+    }
+    for link in &graph.impls {
+        // default implementations are linked to themselves
+        if link.decl != link.implementation {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [style=dotted, arrowhead=empty];\n",
+                escape(&link.decl),
+                escape(&link.implementation)
+            ));
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Writes `graph` to the file given by `--output`, or to stdout.
+pub fn write(graph: &Callgraph, options: &Options) -> io::Result<()> {
+    let rendered = render(graph, options.output_format);
+    match &options.output {
+        Some(path) => File::create(path)?.write_all(rendered.as_bytes()),
+        None => io::stdout().lock().write_all(rendered.as_bytes()),
+    }
+}
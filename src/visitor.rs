@@ -1,20 +1,77 @@
 use rustc_hir::HirId;
-use rustc_hir::def_id::DefId;
-use rustc_middle::ty::TyCtxt;
+use rustc_hir::def::DefKind;
+use rustc_hir::def_id::{CrateNum, DefId, LOCAL_CRATE};
+use rustc_middle::ty::{self, GenericArgsRef, Ty, TyCtxt, TypeckResults};
+use rustc_middle::ty::adjustment::{Adjust, PointerCoercion};
 use rustc_middle::ty::ParamEnvAnd;
+use rustc_middle::ty::print::{with_crate_prefix, with_no_trimmed_paths, with_no_visible_paths};
+use rustc_session::config::CrateType;
 use std::collections::{HashMap, HashSet};
 use rustc_hir::intravisit;
 use rustc_middle::hir::nested_filter;
-use rustc_span::Span;
+use rustc_span::{sym, DesugaringKind, ExpnKind, MacroKind, Span, Symbol};
+
+mod mir;
+
+use crate::options::Options;
+use crate::graph::{
+    Callgraph, DynCoercion, Edge, EdgeKind, EntryPoint, ImplLink, MacroExpansion, Node, NodeId, NodeKind,
+    SourceSpan, UnresolvedCall, UnresolvedReason,
+};
 
 macro_rules! skip_generated_code {
-    ($span: expr) => {
-        if $span.from_expansion() || $span.is_dummy() {
+    ($this: expr, $span: expr) => {
+        if is_generated($span, $this.include_macros) {
             return;
         }
     };
 }
 
+// Whether `span` is not in the source, e.g. from a macro. `for` loops,
+// `.await`, `?` and `async` bodies are desugared, but the code in them is
+// written by the user unless the desugaring itself is in generated code, see
+// `is_hidden_desugaring` for the calls they add. Macros count as source with
+// `--include-macro-expansions`, compiler passes like the test harness never do.
+fn is_generated(span: Span, include_macros: bool) -> bool {
+    if span.is_dummy() {
+        return true;
+    }
+    if !span.from_expansion() {
+        return false;
+    }
+    let expn = span.ctxt().outer_expn_data();
+    let written = match expn.kind {
+        ExpnKind::Desugaring(
+            DesugaringKind::ForLoop | DesugaringKind::Await | DesugaringKind::QuestionMark | DesugaringKind::Async,
+        ) => true,
+        ExpnKind::Macro(..) => include_macros,
+        _ => false,
+    };
+    !written || is_generated(expn.call_site, include_macros)
+}
+
+// Whether the call of `callee` at `span` is added by a desugaring and not
+// recorded. Only the calls that run user code are: `IntoIterator::into_iter`
+// and `Iterator::next` of a `for` loop, `IntoFuture::into_future` and
+// `Future::poll` of an `.await` and the `From::from` converting the error of a
+// `?`. `Try::branch`, `Pin::new_unchecked` and the like are not.
+fn is_hidden_desugaring(tcx: TyCtxt<'_>, span: Span, callee: DefId) -> bool {
+    let lang_items = tcx.lang_items();
+    let recorded = match span.desugaring_kind() {
+        None => return false,
+        Some(DesugaringKind::ForLoop) => [lang_items.into_iter_fn(), lang_items.next_fn()],
+        Some(DesugaringKind::Await) => [lang_items.into_future_fn(), lang_items.future_poll_fn()],
+        Some(DesugaringKind::QuestionMark) => [tcx.get_diagnostic_item(sym::from_fn), None],
+        Some(_) => return true,
+    };
+    !recorded.contains(&Some(callee))
+}
+
+// Whether `span` is generated by a derive, e.g. `#[derive(Clone)]`.
+fn is_derived(span: Span) -> bool {
+    matches!(span.ctxt().outer_expn_data().kind, ExpnKind::Macro(MacroKind::Derive, _))
+}
+
 // Backup self.cur_fn, set cur_fn to id, continue to walk the AST by executing
 // $walk, then restore self.cur_fn.
 macro_rules! push_walk_pop {
@@ -39,12 +96,91 @@ struct Call {
     callee_span: Span,
 }
 
+// A call whose callee could not be determined.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct Unresolved {
+    call_expr: HirId,
+    call_expr_span: Span,
+    caller: Option<DefId>,
+    // the function that was called, before resolution
+    callee: Option<DefId>,
+    reason: UnresolvedReason,
+}
+
+// A call through a closure, function pointer or Fn trait object.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct IndirectCall {
+    call_expr: HirId,
+    call_expr_span: Span,
+    caller: Option<DefId>,
+    // the closure, if the callee's type is a closure type
+    closure: Option<DefId>,
+    // type of the callee expression
+    callee_ty: String,
+}
+
+// Replaces the leading `crate` segments of the paths in `path` by
+// `crate_prefix`, e.g. both in `<crate::S as crate::T>::f`, but not the end of
+// a name like `mycrate::f`.
+fn replace_crate_segments(path: &str, crate_prefix: &str) -> String {
+    let mut replaced = String::with_capacity(path.len());
+    let mut last = 0;
+    for (i, segment) in path.match_indices("crate::") {
+        let before = path[..i].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == ':') {
+            continue;
+        }
+        replaced.push_str(&path[last..i]);
+        replaced.push_str(crate_prefix);
+        last = i + segment.len();
+    }
+    replaced.push_str(&path[last..]);
+    replaced
+}
+
+// The span of the whole definition of `def_id`, e.g. a function with its
+// body. Only the head is known for definitions of other crates.
+fn definition_span(tcx: TyCtxt<'_>, def_id: DefId) -> Span {
+    match def_id.as_local() {
+        Some(local) => tcx.hir().span_with_body(tcx.local_def_id_to_hir_id(local)),
+        None => tcx.def_span(def_id),
+    }
+}
+
+// The type that becomes a trait object by the unsizing coercion from
+// `source` to `target`, e.g. `S` for `Box<S>` to `Box<dyn T>`. None for
+// upcasts from one trait object to another, their types are already known.
+fn dyn_coerced_ty<'tcx>(source: Ty<'tcx>, target: Ty<'tcx>) -> Option<Ty<'tcx>> {
+    match (*source.kind(), *target.kind()) {
+        (ty::Dynamic(..), _) => None,
+        (_, ty::Dynamic(..)) => Some(source),
+        (ty::Ref(_, source, _), ty::Ref(_, target, _))
+        | (ty::Ref(_, source, _), ty::RawPtr(target, _))
+        | (ty::RawPtr(source, _), ty::RawPtr(target, _)) => dyn_coerced_ty(source, target),
+        // `Box`, `Rc` and other smart pointers
+        (ty::Adt(source_def, source_args), ty::Adt(target_def, target_args)) if source_def == target_def => {
+            let (source, target) = source_args.types().zip(target_args.types()).find(|(s, t)| s != t)?;
+            dyn_coerced_ty(source, target)
+        }
+        _ => None,
+    }
+}
+
 pub struct CallgraphVisitor<'tcx> {
     // type context
     tcx: TyCtxt<'tcx>,
+    // whether to record implicit calls
+    record_implicit: bool,
+    // whether to analyze code generated by macros
+    include_macros: bool,
 
     // free functions
     functions: HashSet<(DefId, Span)>,
+    // consts, statics and const expressions with calls outside of functions
+    initializers: HashSet<DefId>,
+    // map the const expressions in initializers and function bodies, e.g.
+    // `[0; f()]`, to the initializer or function they are attributed to
+    nested_consts: HashMap<DefId, DefId>,
     // trait method declarations without default implementation
     method_decls: HashSet<DefId>,
     // map decls to impls
@@ -54,30 +190,635 @@ pub struct CallgraphVisitor<'tcx> {
     static_calls: HashSet<Call>,
     // dynamic calls
     dynamic_calls: HashSet<Call>,
+    // static calls of overloaded operators
+    operator_calls: HashSet<Call>,
+    // calls that are not in the source, only with `--implicit-calls`
+    implicit_calls: HashSet<Call>,
+    // calls of tuple struct and tuple variant constructors
+    ctor_calls: HashSet<Call>,
+    // map the spans of the `into_iter` and `next` calls of the `for` loops in
+    // the source to the iterable as written in the loop. The calls have the
+    // span of the iterable, which may be generated, e.g. by `vec![]`.
+    for_loop_heads: HashMap<Span, Span>,
+    // spans of the `*` operators calling `Deref::deref`, to tell them from
+    // autoderef in MIR
+    overloaded_derefs: HashSet<Span>,
+    // calls through closures, fn pointers and Fn trait objects
+    indirect_calls: HashSet<IndirectCall>,
+    // calls that could not be resolved
+    unresolved_calls: HashSet<Unresolved>,
+    // (enclosing function, type key) of the types coerced to trait objects
+    dyn_coercions: HashSet<(Option<DefId>, String)>,
+    // map closures to their enclosing function
+    closures: HashMap<DefId, Option<DefId>>,
+    // (parent module, name) of the test functions, when compiled with `--test`
+    test_markers: HashSet<(DefId, Symbol)>,
 
     // tracks the current function we're in during AST walk
     cur_fn: Option<DefId>,
+    // typeck results of the body we're in during AST walk
+    maybe_typeck_results: Option<&'tcx TypeckResults<'tcx>>,
 }
 
 impl<'tcx> CallgraphVisitor<'tcx> {
-    pub fn new(tcx: &TyCtxt<'tcx>) -> CallgraphVisitor<'tcx> {
+    pub fn new(tcx: &TyCtxt<'tcx>, options: &Options) -> CallgraphVisitor<'tcx> {
         CallgraphVisitor {
             tcx: *tcx,
+            record_implicit: options.implicit_calls,
+            include_macros: options.include_macro_expansions,
             functions: HashSet::new(),
+            initializers: HashSet::new(),
+            nested_consts: HashMap::new(),
             method_decls: HashSet::new(),
             method_impls: HashMap::new(),
             static_calls: HashSet::new(),
             dynamic_calls: HashSet::new(),
+            operator_calls: HashSet::new(),
+            implicit_calls: HashSet::new(),
+            ctor_calls: HashSet::new(),
+            for_loop_heads: HashMap::new(),
+            overloaded_derefs: HashSet::new(),
+            indirect_calls: HashSet::new(),
+            unresolved_calls: HashSet::new(),
+            dyn_coercions: HashSet::new(),
+            closures: HashMap::new(),
+            test_markers: HashSet::new(),
             cur_fn: None,
+            maybe_typeck_results: None,
+        }
+    }
+
+    fn typeck_results(&self) -> &'tcx TypeckResults<'tcx> {
+        self.maybe_typeck_results
+            .expect("expression outside of a body")
+    }
+
+    fn cur_fn_span(&self) -> Option<Span> {
+        self.cur_fn.map(|caller| definition_span(self.tcx, caller))
+    }
+
+    fn is_trait_method(&self, res: rustc_hir::def::Res) -> bool {
+        match res {
+            rustc_hir::def::Res::Def(DefKind::AssocFn, def_id) => {
+                self.tcx.trait_of_item(def_id).is_some()
+            }
+            _ => false,
         }
     }
 
-    pub fn dump(&self) {
-        dbg!(&self.functions);
-        dbg!(&self.method_decls);
-        dbg!(&self.method_impls);
-        dbg!(&self.static_calls);
-        dbg!(&self.dynamic_calls);
+    fn record_unresolved_call(
+        &mut self,
+        expr: &'tcx rustc_hir::Expr,
+        callee: Option<DefId>,
+        reason: UnresolvedReason,
+    ) {
+        self.unresolved_calls.insert(Unresolved {
+            call_expr: expr.hir_id,
+            call_expr_span: expr.span,
+            caller: self.cur_fn,
+            callee,
+            reason,
+        });
+    }
+
+    // Resolves the call of `fn_def_id` with generic arguments `args` to the
+    // called instance and records it as a dynamic call, or as a call of `kind`,
+    // which is `Static`, `Operator` or `Implicit`.
+    fn record_resolved_call(
+        &mut self,
+        expr: &'tcx rustc_hir::Expr,
+        fn_def_id: DefId,
+        args: GenericArgsRef<'tcx>,
+        kind: EdgeKind,
+    ) {
+        if is_hidden_desugaring(self.tcx, expr.span, fn_def_id) {
+            return;
+        }
+        // reveal opaque types, e.g. the future returned by an `async fn`
+        let param_env = self.tcx.param_env_reveal_all_normalized(expr.hir_id.owner);
+        let args = self.tcx.try_normalize_erasing_regions(param_env, args).unwrap_or(args);
+        let inst = match self.tcx.resolve_instance_raw(ParamEnvAnd{param_env, value: (fn_def_id, args)}) {
+            Ok(Some(inst)) => inst,
+            Ok(None) => {
+                self.record_unresolved_call(expr, Some(fn_def_id), UnresolvedReason::TooGeneric);
+                return;
+            }
+            Err(_) => {
+                self.record_unresolved_call(expr, Some(fn_def_id), UnresolvedReason::ResolveError);
+                return;
+            }
+        };
+        let res_def_id = inst.def_id();
+        match self.tcx.hir().get_if_local(res_def_id) {
+            Some(rustc_hir::Node::TraitItem(rustc_hir::TraitItem{span, ..})) => {
+                // dynamic calls resolve only to the trait method decl
+                self.dynamic_calls.insert(Call {
+                    call_expr: expr.hir_id,
+                    call_expr_span: expr.span,
+                    caller: self.cur_fn,
+                    caller_span: self.cur_fn_span(),
+                    callee: res_def_id,
+                    callee_span: *span,
+                });
+            }
+            Some(rustc_hir::Node::ImplItem(rustc_hir::ImplItem{span, ..})) |
+                    Some(rustc_hir::Node::Item(rustc_hir::Item{span, ..})) |
+                    Some(rustc_hir::Node::ForeignItem(rustc_hir::ForeignItem{span, ..})) => {
+                // calls for which the receiver's type can be resolved
+                let call = Call {
+                    call_expr: expr.hir_id,
+                    call_expr_span: expr.span,
+                    caller: self.cur_fn,
+                    caller_span: self.cur_fn_span(),
+                    callee: res_def_id,
+                    callee_span: *span,
+                };
+                self.calls_of_kind(kind).insert(call);
+            },
+            Some(rustc_hir::Node::Expr(rustc_hir::Expr{kind: rustc_hir::ExprKind::Closure(..), span, ..}))
+                    if self.tcx.is_coroutine(res_def_id) => {
+                // `Future::poll` of an `async` block or fn body, from `.await`
+                let call = Call {
+                    call_expr: expr.hir_id,
+                    call_expr_span: expr.span,
+                    caller: self.cur_fn,
+                    caller_span: self.cur_fn_span(),
+                    callee: res_def_id,
+                    callee_span: *span,
+                };
+                self.calls_of_kind(kind).insert(call);
+            },
+            Some(rustc_hir::Node::Ctor(..)) => {
+                // tuple struct and tuple variant constructors
+                self.ctor_calls.insert(Call {
+                    call_expr: expr.hir_id,
+                    call_expr_span: expr.span,
+                    caller: self.cur_fn,
+                    caller_span: self.cur_fn_span(),
+                    callee: res_def_id,
+                    callee_span: definition_span(self.tcx, res_def_id),
+                });
+            },
+            None => {
+                // functions of other crates, e.g. std
+                let kind = match inst.def {
+                    ty::InstanceKind::Virtual(..) => EdgeKind::Dynamic,
+                    _ if matches!(self.tcx.def_kind(res_def_id), DefKind::Ctor(..)) => EdgeKind::Constructor,
+                    _ => kind,
+                };
+                let call = Call {
+                    call_expr: expr.hir_id,
+                    call_expr_span: expr.span,
+                    caller: self.cur_fn,
+                    caller_span: self.cur_fn_span(),
+                    callee: res_def_id,
+                    callee_span: definition_span(self.tcx, res_def_id),
+                };
+                self.calls_of_kind(kind).insert(call);
+            }
+            Some(_) => {
+                self.record_unresolved_call(expr, Some(res_def_id), UnresolvedReason::UnexpectedCallee);
+            }
+        };
+    }
+
+    fn calls_of_kind(&mut self, kind: EdgeKind) -> &mut HashSet<Call> {
+        match kind {
+            EdgeKind::Dynamic => &mut self.dynamic_calls,
+            EdgeKind::Operator => &mut self.operator_calls,
+            EdgeKind::Implicit => &mut self.implicit_calls,
+            EdgeKind::Constructor => &mut self.ctor_calls,
+            _ => &mut self.static_calls,
+        }
+    }
+
+    // The `Drop::drop` impls called by the drop glue of `ty`. A type with a
+    // destructor is assumed to drop the values of its type arguments, as
+    // e.g. `Vec` and `Box` do.
+    fn destructors(&self, ty: Ty<'tcx>, found: &mut Vec<DefId>, seen: &mut HashSet<Ty<'tcx>>) {
+        if !seen.insert(ty) || !ty.needs_drop(self.tcx, ty::ParamEnv::reveal_all()) {
+            return;
+        }
+        match *ty.kind() {
+            // the value of a `ManuallyDrop` is never dropped
+            ty::Adt(def, _) if def.is_manually_drop() => {}
+            ty::Adt(def, args) => {
+                if let Some(destructor) = self.tcx.adt_destructor(def.did()) {
+                    found.push(destructor.did);
+                    for arg in args.types() {
+                        self.destructors(arg, found, seen);
+                    }
+                }
+                // neither are the fields of a union
+                if !def.is_union() {
+                    for field in def.all_fields() {
+                        self.destructors(field.ty(self.tcx, args), found, seen);
+                    }
+                }
+            }
+            ty::Tuple(tys) => {
+                for ty in tys {
+                    self.destructors(ty, found, seen);
+                }
+            }
+            ty::Array(ty, _) | ty::Slice(ty) => self.destructors(ty, found, seen),
+            ty::Closure(_, args) => {
+                for ty in args.as_closure().upvar_tys() {
+                    self.destructors(ty, found, seen);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // `?` on a `Result<_, E>` in a function returning `Result<_, F>` calls
+    // `<F as From<E>>::from` in `FromResidual::from_residual`. Returns the
+    // `From::from` call for the `from_residual` generic arguments.
+    fn try_conversion(&self, from_residual_args: GenericArgsRef<'tcx>) -> Option<(DefId, GenericArgsRef<'tcx>)> {
+        let (ty::Adt(result, result_args), ty::Adt(residual, residual_args)) =
+            (from_residual_args.type_at(0).kind(), from_residual_args.type_at(1).kind())
+        else {
+            return None;
+        };
+        if !self.tcx.is_diagnostic_item(sym::Result, result.did()) || result != residual {
+            return None;
+        }
+        let from_fn = self.tcx.get_diagnostic_item(sym::from_fn)?;
+        Some((from_fn, self.tcx.mk_args(&[result_args[1], residual_args[1]])))
+    }
+
+    // Records the `From::from` call of a `?`, given the desugared match.
+    fn record_try_conversion(&mut self, expr: &'tcx rustc_hir::Expr) {
+        let rustc_hir::ExprKind::Match(_, arms, _) = expr.kind else {
+            return;
+        };
+        // `return FromResidual::from_residual(residual)`, or `break` in a try block
+        let from_residual = arms.iter().find_map(|arm| match arm.body.kind {
+            rustc_hir::ExprKind::Ret(Some(from_residual)) | rustc_hir::ExprKind::Break(_, Some(from_residual)) => {
+                Some(from_residual)
+            }
+            _ => None,
+        });
+        let Some(from_residual) = from_residual else {
+            return;
+        };
+        let rustc_hir::ExprKind::Call(callee, _) = from_residual.kind else {
+            return;
+        };
+        if let Some(ty::FnDef(_, args)) = self.typeck_results().node_type_opt(callee.hir_id).map(|ty| *ty.kind()) {
+            if let Some((from_fn, args)) = self.try_conversion(args) {
+                self.record_resolved_call(expr, from_fn, args, EdgeKind::Implicit);
+            }
+        }
+    }
+
+    // Records the `Drop::drop` calls when the value bound by `pat` is dropped.
+    fn record_drop(&mut self, pat: &'tcx rustc_hir::Pat) {
+        // e.g. the iterator of a `for` loop
+        if pat.span.desugaring_kind().is_some() {
+            return;
+        }
+        let Some(ty) = self.typeck_results().node_type_opt(pat.hir_id) else {
+            return;
+        };
+        let mut destructors = vec![];
+        self.destructors(ty, &mut destructors, &mut HashSet::new());
+        for destructor in destructors {
+            self.implicit_calls.insert(Call {
+                call_expr: pat.hir_id,
+                call_expr_span: pat.span,
+                caller: self.cur_fn,
+                caller_span: self.cur_fn_span(),
+                callee: destructor,
+                callee_span: definition_span(self.tcx, destructor),
+            });
+        }
+    }
+
+    // Records a call whose callee is a value rather than a fn item.
+    fn record_indirect_call(&mut self, expr: &'tcx rustc_hir::Expr, callee_ty: Ty<'tcx>) {
+        let callee_ty = callee_ty.peel_refs();
+        let closure = match *callee_ty.kind() {
+            ty::Closure(def_id, _) => Some(def_id),
+            _ => None,
+        };
+        self.indirect_calls.insert(IndirectCall {
+            call_expr: expr.hir_id,
+            call_expr_span: expr.span,
+            caller: self.cur_fn,
+            closure,
+            callee_ty: callee_ty.to_string(),
+        });
+    }
+
+    fn record_dyn_coercion(&mut self, function: Option<DefId>, source: Ty<'tcx>, target: Ty<'tcx>) {
+        if let Some(ty) = dyn_coerced_ty(source, target) {
+            self.dyn_coercions.insert((function, self.type_key(ty)));
+        }
+    }
+
+    // Names a type the same in every crate, see `ImplLink::self_ty`.
+    fn type_key(&self, ty: Ty<'tcx>) -> String {
+        match *ty.kind() {
+            ty::Adt(def, _) => self.node_id(def.did()),
+            ty::Closure(def_id, _) | ty::FnDef(def_id, _) | ty::Foreign(def_id) => self.node_id(def_id),
+            _ => with_no_trimmed_paths!(self.tcx.erase_regions(ty).to_string()),
+        }
+    }
+
+    fn node_kind(&self, def_id: DefId, defined: &HashSet<DefId>) -> NodeKind {
+        if self.method_decls.contains(&def_id) {
+            return if defined.contains(&def_id) {
+                NodeKind::MethodDefault
+            } else {
+                NodeKind::MethodDecl
+            };
+        }
+        // constructors have no body, in any crate
+        if let DefKind::Ctor(..) = self.tcx.def_kind(def_id) {
+            return NodeKind::Constructor;
+        }
+        if self.initializers.contains(&def_id) {
+            return match self.tcx.def_kind(def_id) {
+                DefKind::Static { .. } => NodeKind::StaticInit,
+                _ => NodeKind::ConstInit,
+            };
+        }
+        if !def_id.is_local() {
+            return NodeKind::External;
+        }
+        if !defined.contains(&def_id) {
+            return NodeKind::Unknown;
+        }
+        match self.tcx.def_kind(def_id) {
+            DefKind::AssocFn => {
+                let in_trait_impl = self
+                    .tcx
+                    .impl_of_method(def_id)
+                    .and_then(|impl_id| self.tcx.trait_id_of_impl(impl_id))
+                    .is_some();
+                if in_trait_impl {
+                    NodeKind::MethodImpl
+                } else {
+                    NodeKind::AssocFn
+                }
+            }
+            DefKind::Closure => NodeKind::Closure,
+            _ => NodeKind::Function,
+        }
+    }
+
+    fn entry_point(&self, def_id: DefId) -> Option<EntryPoint> {
+        if self.tcx.entry_fn(()).is_some_and(|(main, _)| main == def_id) {
+            return Some(EntryPoint::Main);
+        }
+        if self.tcx.def_kind(def_id) == DefKind::Fn {
+            let key = (self.tcx.parent(def_id), self.tcx.item_name(def_id));
+            if self.test_markers.contains(&key) {
+                return Some(EntryPoint::Test);
+            }
+        }
+        None
+    }
+
+    // Bins usually have the same crate name as the lib of their package. Set
+    // their def paths apart, so the lib's nodes are not merged with the bin's.
+    // Cargo sets CARGO_BIN_NAME for bins and examples, also in test builds.
+    fn is_bin_crate(&self) -> bool {
+        std::env::var_os("CARGO_BIN_NAME").is_some()
+            || (self.tcx.crate_types().contains(&CrateType::Executable)
+                && !self.tcx.sess.is_test_crate())
+    }
+
+    // The crate name used in node ids, bins get a `[bin]` suffix.
+    fn crate_id(&self, krate: CrateNum) -> String {
+        let crate_name = self.tcx.crate_name(krate);
+        if krate == LOCAL_CRATE && self.is_bin_crate() {
+            format!("{}[bin]", crate_name)
+        } else {
+            crate_name.to_string()
+        }
+    }
+
+    // Stable node id, see `NodeId`. Paths are printed without re-exports and
+    // with `crate::` for local items, which is replaced by the crate name, so
+    // the id is the same in every crate.
+    fn node_id(&self, def_id: DefId) -> NodeId {
+        let path = with_no_visible_paths!(with_no_trimmed_paths!(with_crate_prefix!(
+            self.tcx.def_path_str(def_id)
+        )));
+        let crate_prefix = format!("{}::", self.crate_id(LOCAL_CRATE));
+        let path = replace_crate_segments(&path, &crate_prefix);
+        // before edition 2018, local paths are printed without `crate::`
+        let mut id = if def_id.is_local() && !path.starts_with(&crate_prefix) && !path.starts_with('<') {
+            crate_prefix + &path
+        } else {
+            path
+        };
+
+        // `def_path_str` leaves out the disambiguator, which is needed when
+        // e.g. the test harness adds a const with the same name as a function.
+        // Closures and anonymous consts already print it, as `{closure#1}`.
+        let disambiguator = self.tcx.def_key(def_id).disambiguated_data.disambiguator;
+        let printed = matches!(self.tcx.def_kind(def_id), DefKind::Closure | DefKind::AnonConst | DefKind::InlineConst);
+        if disambiguator != 0 && !printed {
+            id.push_str(&format!("#{}", disambiguator));
+        }
+        id
+    }
+
+    fn source_span(&self, span: Span) -> SourceSpan {
+        let source_map = self.tcx.sess.source_map();
+        let lo = source_map.lookup_char_pos(span.lo());
+        let hi = source_map.lookup_char_pos(span.hi());
+        SourceSpan {
+            file: lo.file.name.prefer_local().to_string(),
+            line: lo.line,
+            column: lo.col.0 + 1,
+            end_line: hi.line,
+            end_column: hi.col.0 + 1,
+        }
+    }
+
+    // Where a call at `span` is in the source, the calls of a `for` loop are at
+    // the iterable as written in the loop.
+    fn written_span(&self, span: Span) -> Span {
+        self.for_loop_heads.get(&span).copied().unwrap_or(span)
+    }
+
+    // The def path hash of `def_id` in hex, zero padded so that different
+    // hashes are never printed the same.
+    fn def_path_hash(&self, def_id: DefId) -> String {
+        let (high, low) = self.tcx.def_path_hash(def_id).0.split();
+        format!("{:016x}{:016x}", high.as_u64(), low.as_u64())
+    }
+
+    // The location of the definition of `def_id`, if it has one.
+    fn definition_source_span(&self, def_id: DefId) -> Option<SourceSpan> {
+        let span = definition_span(self.tcx, def_id);
+        (!span.is_dummy()).then(|| self.source_span(span))
+    }
+
+    // The macro invocations `span` is expanded from, innermost first.
+    fn macro_backtrace(&self, span: Span) -> Vec<MacroExpansion> {
+        span.macro_backtrace()
+            .filter(|expn| matches!(expn.kind, ExpnKind::Macro(..)))
+            .map(|expn| MacroExpansion {
+                name: expn.kind.descr(),
+                call_site: self.source_span(expn.call_site),
+            })
+            .collect()
+    }
+
+    /// Converts the collected definitions and calls into a `Callgraph`.
+    pub fn into_callgraph(self) -> Callgraph {
+        let mut graph = Callgraph::new(self.tcx.crate_name(LOCAL_CRATE).to_string());
+
+        let spans: HashMap<DefId, Span> = self.functions.iter().copied().collect();
+        let defined: HashSet<DefId> = spans.keys().copied().collect();
+
+        // every def id that shows up anywhere becomes a node
+        let mut def_ids: HashSet<DefId> = HashSet::new();
+        def_ids.extend(defined.iter().copied());
+        def_ids.extend(self.method_decls.iter().copied());
+        for (decl, impls) in self.method_impls.iter() {
+            def_ids.insert(*decl);
+            def_ids.extend(impls.iter().copied());
+        }
+        let calls = [
+            &self.static_calls,
+            &self.dynamic_calls,
+            &self.operator_calls,
+            &self.implicit_calls,
+            &self.ctor_calls,
+        ];
+        for call in calls.into_iter().flatten() {
+            def_ids.extend(call.caller);
+            def_ids.insert(call.callee);
+        }
+        for call in self.indirect_calls.iter() {
+            def_ids.extend(call.caller);
+            def_ids.extend(call.closure);
+        }
+        for call in self.unresolved_calls.iter() {
+            def_ids.extend(call.caller);
+        }
+        for (function, _) in self.dyn_coercions.iter() {
+            def_ids.extend(*function);
+        }
+
+        let mut ids: HashMap<DefId, NodeId> = HashMap::new();
+        for def_id in def_ids {
+            let span = match spans.get(&def_id) {
+                Some(span) => Some(*span),
+                None => Some(definition_span(self.tcx, def_id)).filter(|span| !span.is_dummy()),
+            };
+            let id = self.node_id(def_id);
+            graph.nodes.push(Node {
+                id: id.clone(),
+                kind: self.node_kind(def_id, &defined),
+                path: self.tcx.def_path_str(def_id),
+                crate_name: self.tcx.crate_name(def_id.krate).to_string(),
+                def_path_hash: Some(self.def_path_hash(def_id)),
+                span: span.map(|span| self.source_span(span)),
+                entry_point: self.entry_point(def_id),
+                expansion: span.map(|span| self.macro_backtrace(span)).unwrap_or_default(),
+            });
+            ids.insert(def_id, id);
+        }
+
+        // indirect calls to anything but a closure go to one node per callee type
+        let mut ty_ids: HashMap<&str, NodeId> = HashMap::new();
+        for call in self.indirect_calls.iter().filter(|call| call.closure.is_none()) {
+            ty_ids.entry(&call.callee_ty).or_insert_with(|| {
+                let id = format!("{}::<indirect {}>", self.crate_id(LOCAL_CRATE), call.callee_ty);
+                graph.nodes.push(Node {
+                    id: id.clone(),
+                    kind: NodeKind::Indirect,
+                    path: call.callee_ty.clone(),
+                    crate_name: self.tcx.crate_name(LOCAL_CRATE).to_string(),
+                    def_path_hash: None,
+                    span: None,
+                    entry_point: None,
+                    expansion: vec![],
+                });
+                id
+            });
+        }
+
+        for (calls, kind) in [
+            (&self.static_calls, EdgeKind::Static),
+            (&self.dynamic_calls, EdgeKind::Dynamic),
+            (&self.operator_calls, EdgeKind::Operator),
+            (&self.implicit_calls, EdgeKind::Implicit),
+            (&self.ctor_calls, EdgeKind::Constructor),
+        ] {
+            graph.edges.extend(calls.iter().map(|call| Edge {
+                caller: call.caller.map(|caller| ids[&caller].clone()),
+                callee: ids[&call.callee].clone(),
+                kind,
+                span: Some(self.source_span(self.written_span(call.call_expr_span))),
+                caller_span: call.caller_span.map(|span| self.source_span(span)),
+                callee_span: (!call.callee_span.is_dummy()).then(|| self.source_span(call.callee_span)),
+                expansion: self.macro_backtrace(self.written_span(call.call_expr_span)),
+            }));
+        }
+        graph.edges.extend(self.indirect_calls.iter().map(|call| {
+            let callee = match call.closure {
+                Some(closure) => ids[&closure].clone(),
+                None => ty_ids[call.callee_ty.as_str()].clone(),
+            };
+            Edge {
+                caller: call.caller.map(|caller| ids[&caller].clone()),
+                callee,
+                kind: EdgeKind::Indirect,
+                span: Some(self.source_span(call.call_expr_span)),
+                caller_span: call.caller.and_then(|caller| self.definition_source_span(caller)),
+                callee_span: call.closure.and_then(|closure| self.definition_source_span(closure)),
+                expansion: self.macro_backtrace(call.call_expr_span),
+            }
+        }));
+
+        graph.unresolved.extend(self.unresolved_calls.iter().map(|call| UnresolvedCall {
+            caller: call.caller.map(|caller| ids[&caller].clone()),
+            callee: call.callee.map(|callee| self.node_id(callee)),
+            reason: call.reason,
+            span: self.source_span(self.written_span(call.call_expr_span)),
+        }));
+
+        for (closure, parent) in self.closures.iter() {
+            graph.edges.push(Edge {
+                caller: parent.map(|parent| ids[&parent].clone()),
+                callee: ids[closure].clone(),
+                kind: EdgeKind::Closure,
+                span: spans.get(closure).map(|span| self.source_span(*span)),
+                caller_span: parent.and_then(|parent| self.definition_source_span(parent)),
+                callee_span: self.definition_source_span(*closure),
+                expansion: spans.get(closure).map(|span| self.macro_backtrace(*span)).unwrap_or_default(),
+            });
+        }
+
+        for (decl, impls) in self.method_impls.iter() {
+            for implementation in impls {
+                let self_ty = self
+                    .tcx
+                    .impl_of_method(*implementation)
+                    .map(|impl_id| self.type_key(self.tcx.type_of(impl_id).instantiate_identity()));
+                graph.impls.push(ImplLink {
+                    decl: ids[decl].clone(),
+                    implementation: ids[implementation].clone(),
+                    self_ty,
+                });
+            }
+        }
+
+        graph.dyn_coercions.extend(self.dyn_coercions.iter().map(|(function, ty)| DynCoercion {
+            function: function.map(|function| ids[&function].clone()),
+            ty: ty.clone(),
+        }));
+
+        graph.sort();
+        graph
     }
 }
 
@@ -89,67 +830,143 @@ impl<'tcx> intravisit::Visitor<'tcx> for CallgraphVisitor<'tcx> {
         self.tcx.hir()
     }
 
+    fn visit_nested_body(&mut self, body_id: rustc_hir::BodyId) {
+        let prev_results = self.maybe_typeck_results;
+        self.maybe_typeck_results = Some(self.tcx.typeck_body(body_id));
+        let body = self.tcx.hir().body(body_id);
+        let owner = self.tcx.hir().body_owner_def_id(body_id).to_def_id();
+        let initializer = matches!(
+            self.tcx.def_kind(owner),
+            DefKind::Const | DefKind::AssocConst | DefKind::Static { .. } | DefKind::AnonConst | DefKind::InlineConst
+        );
+        if initializer && self.cur_fn.is_none() {
+            // calls outside of functions belong to the const or static
+            self.initializers.insert(owner);
+            push_walk_pop!(self, owner, intravisit::walk_body(self, body));
+        } else {
+            if let Some(cur_fn) = self.cur_fn.filter(|_| initializer) {
+                self.nested_consts.insert(owner, cur_fn);
+            }
+            intravisit::walk_body(self, body);
+        }
+        self.maybe_typeck_results = prev_results;
+    }
+
     fn visit_expr(&mut self, expr: &'tcx rustc_hir::Expr) {
-        skip_generated_code!(expr.span);
+        // `match IntoIterator::into_iter(<iterable>) { mut iter => loop { .. } }`:
+        // whether the loop's calls are in the source depends on the `for`, not
+        // on the iterable
+        if let rustc_hir::ExprKind::Match(head, [arm], rustc_hir::MatchSource::ForLoopDesugar) = expr.kind {
+            let is_for_loop = matches!(arm.body.kind, rustc_hir::ExprKind::Loop(_, _, rustc_hir::LoopSource::ForLoop, _));
+            if is_for_loop && !is_generated(expr.span, self.include_macros) {
+                let iterable = head.span.parent_callsite().unwrap_or(head.span);
+                let written = expr.span.parent_callsite().and_then(|for_span| iterable.find_ancestor_in_same_ctxt(for_span));
+                self.for_loop_heads.insert(head.span, written.unwrap_or(iterable));
+            }
+        }
+        if is_generated(expr.span, self.include_macros) && !self.for_loop_heads.contains_key(&expr.span) {
+            // the arguments of a macro are written by the user, e.g. `f()` in
+            // `println!("{}", f())`
+            intravisit::walk_expr(self, expr);
+            return;
+        }
+
+        let typeck_results = self.typeck_results();
+        if let Some(mut ty) = typeck_results.node_type_opt(expr.hir_id) {
+            for adjustment in typeck_results.expr_adjustments(expr) {
+                match adjustment.kind {
+                    Adjust::Pointer(PointerCoercion::Unsize) => {
+                        self.record_dyn_coercion(self.cur_fn, ty, adjustment.target);
+                    }
+                    // autoderef through `Deref::deref` or `DerefMut::deref_mut`
+                    Adjust::Deref(Some(overloaded)) if self.record_implicit => {
+                        if let ty::FnDef(def_id, args) = *overloaded.method_call(self.tcx, ty).kind() {
+                            self.record_resolved_call(expr, def_id, args, EdgeKind::Implicit);
+                        }
+                    }
+                    _ => {}
+                }
+                ty = adjustment.target;
+            }
+        }
 
         let hir_id = expr.hir_id;
         match expr.kind {
-            rustc_hir::ExprKind::Call(
-                    rustc_hir::Expr{
-                        kind: rustc_hir::ExprKind::Path(ref qpath),
-                        ..
-                    }, _) => {
-                if let rustc_hir::QPath::Resolved(_, p) = qpath {
-                    if let rustc_hir::def::Res::Def(_, def_id) = p.res {
-                        self.static_calls.insert(Call {
-                            call_expr: hir_id,
-                            call_expr_span: expr.span,
-                            caller: self.cur_fn,
-                            caller_span: None,
-                            callee: def_id,
-                            callee_span: p.span,
-                        });
+            rustc_hir::ExprKind::Call(callee, _) => {
+                let Some(callee_ty) = self.typeck_results().node_type_opt(callee.hir_id) else {
+                    self.record_unresolved_call(expr, None, UnresolvedReason::UnknownCalleeType);
+                    intravisit::walk_expr(self, expr);
+                    return;
+                };
+                match (&callee.kind, *callee_ty.kind()) {
+                    // e.g. `Try::branch` of a `?`
+                    (_, ty::FnDef(def_id, _)) if is_hidden_desugaring(self.tcx, expr.span, def_id) => {}
+                    (rustc_hir::ExprKind::Path(rustc_hir::QPath::Resolved(_, p)), ty::FnDef(fn_def_id, args))
+                            if !self.is_trait_method(p.res) => {
+                        if let rustc_hir::def::Res::Def(def_kind, def_id) = p.res {
+                            let kind = match def_kind {
+                                // `Some(x)`, `Wrapper(v)`
+                                DefKind::Ctor(..) => EdgeKind::Constructor,
+                                _ => EdgeKind::Static,
+                            };
+                            let call = Call {
+                                call_expr: hir_id,
+                                call_expr_span: expr.span,
+                                caller: self.cur_fn,
+                                caller_span: self.cur_fn_span(),
+                                callee: def_id,
+                                callee_span: definition_span(self.tcx, def_id),
+                            };
+                            self.calls_of_kind(kind).insert(call);
+                        } else {
+                            // a local holding a fn item, e.g. `let f = foo; f(1)`
+                            self.record_resolved_call(expr, fn_def_id, args, EdgeKind::Static);
+                        }
+                    }
+                    (_, ty::FnDef(def_id, args)) => {
+                        // `S::new()`, `<T as Trait>::f()`, `Default::default()`:
+                        // the callee depends on the types, ask typeck
+                        self.record_resolved_call(expr, def_id, args, EdgeKind::Static);
+                    }
+                    _ => {
+                        // closures, fn pointers and Fn trait objects
+                        self.record_indirect_call(expr, callee_ty);
                     }
                 }
             },
+            rustc_hir::ExprKind::Closure(closure) => {
+                let def_id = closure.def_id.to_def_id();
+                self.functions.insert((def_id, expr.span));
+                self.closures.insert(def_id, self.cur_fn);
+
+                push_walk_pop!(self, def_id, intravisit::walk_expr(self, expr));
+
+                return;
+            },
             rustc_hir::ExprKind::MethodCall(_, _, _, _) => {
-                let o_def_id = hir_id.owner;
-                let typeck_tables = self.tcx.typeck(o_def_id);
+                let typeck_tables = self.typeck_results();
                 let substs = typeck_tables.node_args(hir_id);
-                let method_id = typeck_tables.type_dependent_def_id(hir_id).expect("fail");
-                let param_env = self.tcx.param_env(method_id);
-                if let Ok(Some(inst)) =
-                    self.tcx.resolve_instance_raw(ParamEnvAnd{param_env, value: (method_id, substs)})
-                {
-                    let res_def_id = inst.def_id();
-                    match self.tcx.hir().get_if_local(res_def_id) {
-                        Some(rustc_hir::Node::TraitItem(rustc_hir::TraitItem{span, ..})) => {
-                            // dynamic calls resolve only to the trait method decl
-                            self.dynamic_calls.insert(Call {
-                                call_expr: hir_id,
-                                call_expr_span: expr.span,
-                                caller: self.cur_fn,
-                                caller_span: None,
-                                callee: res_def_id,
-                                callee_span: *span,
-                            });
-                        }
-                        Some(rustc_hir::Node::ImplItem(rustc_hir::ImplItem{span, ..})) |
-                                Some(rustc_hir::Node::Item(rustc_hir::Item{span, ..})) |
-                                Some(rustc_hir::Node::ForeignItem(rustc_hir::ForeignItem{span, ..})) => {
-                            // calls for which the receiver's type can be resolved
-                            self.static_calls.insert(Call {
-                                call_expr: hir_id,
-                                call_expr_span: expr.span,
-                                caller: self.cur_fn,
-                                caller_span: None,
-                                callee: res_def_id,
-                                callee_span: *span,
-                            });
-                        },
-                        None => (),
-                        _ => todo!()
-                    };
+                match typeck_tables.type_dependent_def_id(hir_id) {
+                    Some(method_id) => self.record_resolved_call(expr, method_id, substs, EdgeKind::Static),
+                    None => self.record_unresolved_call(expr, None, UnresolvedReason::UnresolvedMethod),
+                }
+            },
+            rustc_hir::ExprKind::Match(_, _, rustc_hir::MatchSource::TryDesugar(_)) if self.record_implicit => {
+                self.record_try_conversion(expr);
+            },
+            rustc_hir::ExprKind::Binary(..)
+            | rustc_hir::ExprKind::AssignOp(..)
+            | rustc_hir::ExprKind::Unary(..)
+            | rustc_hir::ExprKind::Index(..) => {
+                // typeck keeps a method only for operators on user-defined
+                // types, e.g. `Add::add` for `a + b` or `Deref::deref` for `*p`
+                let typeck_tables = self.typeck_results();
+                if let Some(method_id) = typeck_tables.type_dependent_def_id(hir_id) {
+                    if let rustc_hir::ExprKind::Unary(rustc_hir::UnOp::Deref, _) = expr.kind {
+                        self.overloaded_derefs.insert(expr.span);
+                    }
+                    let substs = typeck_tables.node_args(hir_id);
+                    self.record_resolved_call(expr, method_id, substs, EdgeKind::Operator);
                 }
             },
             _ => {},
@@ -158,8 +975,31 @@ impl<'tcx> intravisit::Visitor<'tcx> for CallgraphVisitor<'tcx> {
         intravisit::walk_expr(self, expr);
     }
 
+    fn visit_pat(&mut self, pat: &'tcx rustc_hir::Pat) {
+        skip_generated_code!(self, pat.span);
+
+        // a binding owns its value, which is dropped at the end of its scope
+        if self.record_implicit {
+            if let rustc_hir::PatKind::Binding(..) = pat.kind {
+                self.record_drop(pat);
+            }
+        }
+        intravisit::walk_pat(self, pat)
+    }
+
     fn visit_item(&mut self, item: &'tcx rustc_hir::Item) {
-        skip_generated_code!(item.span);
+        // The test harness turns `#[test] fn foo` into `fn foo` and a generated
+        // `const foo` with a `#[rustc_test_marker]`.
+        if let rustc_hir::ItemKind::Const(..) = item.kind {
+            if self.tcx.hir().attrs(item.hir_id()).iter().any(|attr| attr.has_name(sym::rustc_test_marker)) {
+                let parent = self.tcx.parent(item.owner_id.to_def_id());
+                self.test_markers.insert((parent, item.ident.name));
+                // its body is generated, also with `--include-macro-expansions`
+                return;
+            }
+        }
+
+        skip_generated_code!(self, item.span);
 
         let hir_id = item.hir_id();
         if let rustc_hir::ItemKind::Fn(_, _, _) = item.kind {
@@ -175,7 +1015,7 @@ impl<'tcx> intravisit::Visitor<'tcx> for CallgraphVisitor<'tcx> {
     }
 
     fn visit_trait_item(&mut self, ti: &'tcx rustc_hir::TraitItem) {
-        skip_generated_code!(ti.span); // TODO ?do we want this
+        skip_generated_code!(self, ti.span); // TODO ?do we want this
 
         let hir_id = ti.hir_id();
         let def_id = hir_id.owner.to_def_id();
@@ -206,7 +1046,12 @@ impl<'tcx> intravisit::Visitor<'tcx> for CallgraphVisitor<'tcx> {
     // self.tcx.hir().hir_to_pretty_string(ty.hir_id)
 
     fn visit_impl_item(&mut self, ii: &'tcx rustc_hir::ImplItem) {
-        skip_generated_code!(ii.span);
+        // derived methods are called like written ones, so they are nodes
+        // even if their bodies are skipped
+        let derived = is_derived(ii.span);
+        if !derived {
+            skip_generated_code!(self, ii.span);
+        }
 
         let hir_id = ii.hir_id();
         let def_id = hir_id.owner.to_def_id();
@@ -241,6 +1086,9 @@ impl<'tcx> intravisit::Visitor<'tcx> for CallgraphVisitor<'tcx> {
                     .push(def_id);
             }
 
+            if derived && is_generated(ii.span, self.include_macros) {
+                return;
+            }
             push_walk_pop!(self, def_id, intravisit::walk_impl_item(self, ii));
 
             return;
@@ -0,0 +1,422 @@
+// The MIR backend. Starting from the entry points, it walks the MIR of every
+// reachable instance with the generic arguments of that instance, so a call
+// through a generic parameter resolves to the impl of the concrete type.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use rustc_hir::HirId;
+use rustc_hir::def::{CtorKind, DefKind};
+use rustc_hir::def_id::DefId;
+use rustc_middle::mir::{
+    AggregateKind, CallSource, CastKind, ClearCrossCrate, Rvalue, SourceInfo, StatementKind, TerminatorKind,
+};
+use rustc_middle::ty::adjustment::PointerCoercion;
+use rustc_middle::ty::{self, Instance, InstanceKind, ParamEnv, Ty};
+use rustc_span::Span;
+
+use super::{definition_span, is_generated, is_hidden_desugaring, Call, CallgraphVisitor, IndirectCall, Unresolved};
+use crate::graph::UnresolvedReason;
+
+impl<'tcx> CallgraphVisitor<'tcx> {
+    // `main` and the tests, or every function that needs no generic arguments
+    // if there is neither, e.g. in a lib. Initializers and the const
+    // expressions in them and in functions are evaluated at compile time, so
+    // all of them are roots.
+    fn mir_roots(&self) -> Vec<Instance<'tcx>> {
+        let mut roots: Vec<DefId> = self
+            .functions
+            .iter()
+            .map(|(def_id, _)| *def_id)
+            .filter(|def_id| self.entry_point(*def_id).is_some())
+            .collect();
+        if roots.is_empty() {
+            roots = self
+                .functions
+                .iter()
+                .map(|(def_id, _)| *def_id)
+                .filter(|def_id| {
+                    self.tcx.def_kind(*def_id) != DefKind::Closure
+                        && !self.tcx.generics_of(*def_id).requires_monomorphization(self.tcx)
+                })
+                .collect();
+        }
+        let initializers = self
+            .initializers
+            .iter()
+            .chain(self.nested_consts.keys())
+            .copied()
+            .filter(|def_id| !self.tcx.generics_of(*def_id).requires_monomorphization(self.tcx));
+        roots.extend(initializers);
+        roots.into_iter().map(|def_id| Instance::mono(self.tcx, def_id)).collect()
+    }
+
+    fn has_mir(&self, instance: Instance<'tcx>) -> bool {
+        match instance.def {
+            InstanceKind::Item(def_id) => self.tcx.is_mir_available(def_id),
+            InstanceKind::Intrinsic(_) | InstanceKind::Virtual(..) => false,
+            // shims are built on demand
+            _ => true,
+        }
+    }
+
+    /// Replaces the calls found in HIR by the calls in the MIR of all
+    /// instances reachable from the entry points. Instances of other crates
+    /// are walked to find e.g. the closures passed to them, but only calls in
+    /// local functions are recorded.
+    pub fn collect_mir_calls(&mut self) {
+        self.static_calls.clear();
+        self.dynamic_calls.clear();
+        self.operator_calls.clear();
+        self.implicit_calls.clear();
+        self.ctor_calls.clear();
+        self.indirect_calls.clear();
+        self.unresolved_calls.clear();
+        self.dyn_coercions.clear();
+
+        let tcx = self.tcx;
+        let param_env = ParamEnv::reveal_all();
+        let roots = self.mir_roots();
+        let mut seen: HashSet<Instance<'tcx>> = roots.iter().copied().collect();
+        let mut queue: VecDeque<Instance<'tcx>> = roots.into();
+        // the instance each instance was first reached from, see `recursion_depth`
+        let mut parents: HashMap<Instance<'tcx>, Instance<'tcx>> = HashMap::new();
+        let recursion_limit = tcx.recursion_limit();
+        let for_loop_heads = std::mem::take(&mut self.for_loop_heads);
+
+        while let Some(instance) = queue.pop_front() {
+            if !self.has_mir(instance) {
+                continue;
+            }
+            // the calls in a nested const expression belong to its owner, like in HIR
+            let caller = match instance.def {
+                InstanceKind::Item(def_id) if def_id.is_local() => {
+                    Some(self.nested_consts.get(&def_id).copied().unwrap_or(def_id))
+                }
+                _ => None,
+            };
+            // the generic const expressions in a function, e.g. `[0; size_of::<T>()]`,
+            // are evaluated with the generic arguments of each of its instances
+            if let InstanceKind::Item(def_id) = instance.def {
+                let nested = self.nested_consts.iter().filter(|(_, owner)| **owner == def_id);
+                for (nested, _) in nested {
+                    // in a closure, they have the generic parameters of the
+                    // function, which come first in the arguments of the closure
+                    let generics = tcx.generics_of(*nested);
+                    if !generics.requires_monomorphization(tcx) || generics.parent_count > instance.args.len() {
+                        continue;
+                    }
+                    let parent_args = tcx.mk_args(&instance.args[..generics.parent_count]);
+                    let args = if tcx.def_kind(*nested) == DefKind::InlineConst {
+                        // inline consts have a parameter for their type as well
+                        let value = tcx.hir().body_owned_by(nested.expect_local()).value;
+                        let ty = tcx.typeck(nested.expect_local()).node_type(value.hir_id);
+                        let ty = instance.instantiate_mir_and_normalize_erasing_regions(
+                            tcx,
+                            param_env,
+                            ty::EarlyBinder::bind(ty),
+                        );
+                        let parts = ty::InlineConstArgsParts { parent_args, ty };
+                        ty::InlineConstArgs::new(tcx, parts).args
+                    } else {
+                        parent_args
+                    };
+                    let nested = Instance::new(*nested, args);
+                    if seen.insert(nested) {
+                        parents.insert(nested, instance);
+                        queue.push_back(nested);
+                    }
+                }
+            }
+
+            let body = tcx.instance_mir(instance.def);
+            let monomorphize = |ty: Ty<'tcx>| {
+                instance.instantiate_mir_and_normalize_erasing_regions(tcx, param_env, ty::EarlyBinder::bind(ty))
+            };
+            // the caller and call expression of a call at `source_info`. Only
+            // calls in local functions outside of macros are recorded, unless
+            // macros are included, see `for_loop_heads` for `for` loops.
+            let include_macros = self.include_macros;
+            let call_site = |source_info: &SourceInfo| {
+                let call_expr = match &body.source_scopes[source_info.scope].local_data {
+                    ClearCrossCrate::Set(scope) => Some(scope.lint_root),
+                    ClearCrossCrate::Clear => None,
+                };
+                let span = source_info.span;
+                caller
+                    .zip(call_expr)
+                    .filter(|_| !is_generated(span, include_macros) || for_loop_heads.contains_key(&span))
+            };
+            for block in body.basic_blocks.iter() {
+                for statement in &block.statements {
+                    let StatementKind::Assign(assign) = &statement.kind else {
+                        continue;
+                    };
+                    match &assign.1 {
+                        // the types coerced to trait objects, also in other crates
+                        Rvalue::Cast(CastKind::PointerCoercion(PointerCoercion::Unsize), operand, target) => {
+                            let source = monomorphize(operand.ty(body, tcx));
+                            self.record_dyn_coercion(caller, source, monomorphize(*target));
+                        }
+                        // `Some(x)` is built in place, without calling the constructor
+                        Rvalue::Aggregate(kind, _) => {
+                            let AggregateKind::Adt(adt_id, variant, ..) = **kind else {
+                                continue;
+                            };
+                            let Some((CtorKind::Fn, ctor)) = tcx.adt_def(adt_id).variant(variant).ctor else {
+                                continue;
+                            };
+                            if let Some((caller, call_expr)) = call_site(&statement.source_info) {
+                                self.ctor_calls.insert(Call {
+                                    call_expr,
+                                    call_expr_span: statement.source_info.span,
+                                    caller: Some(caller),
+                                    caller_span: Some(definition_span(tcx, caller)),
+                                    callee: ctor,
+                                    callee_span: definition_span(tcx, ctor),
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+
+                let terminator = block.terminator();
+                let span = terminator.source_info.span;
+                let record = call_site(&terminator.source_info);
+
+                match &terminator.kind {
+                    TerminatorKind::Drop { place, .. } => {
+                        let ty = monomorphize(place.ty(body, tcx).ty);
+                        let drop = Instance::resolve_drop_in_place(tcx, ty);
+                        if seen.insert(drop) {
+                            parents.insert(drop, instance);
+                            queue.push_back(drop);
+                        }
+                        // e.g. the iterator of a `for` loop
+                        let desugared = span.desugaring_kind().is_some();
+                        if let Some((caller, call_expr)) = record.filter(|_| self.record_implicit && !desugared) {
+                            self.record_mir_drop(call_expr, span, caller, ty);
+                        }
+                    }
+                    TerminatorKind::Call { func, call_source, .. } => {
+                        let func_ty = monomorphize(func.ty(body, tcx));
+                        let resolved = match *func_ty.kind() {
+                            ty::FnDef(def_id, args) => match Instance::try_resolve(tcx, param_env, def_id, args) {
+                                // polymorphic recursion, the instances would never end
+                                Ok(Some(callee))
+                                    if !recursion_limit
+                                        .value_within_limit(recursion_depth(&parents, instance, callee.def_id())) =>
+                                {
+                                    Err((def_id, UnresolvedReason::TooGeneric))
+                                }
+                                Ok(Some(callee)) => {
+                                    if seen.insert(callee) {
+                                        parents.insert(callee, instance);
+                                        queue.push_back(callee);
+                                    }
+                                    Ok(Some(callee))
+                                }
+                                Ok(None) => Err((def_id, UnresolvedReason::TooGeneric)),
+                                Err(_) => Err((def_id, UnresolvedReason::ResolveError)),
+                            },
+                            // fn pointers
+                            _ => Ok(None),
+                        };
+
+                        let mut record = record;
+                        if let ty::FnDef(def_id, args) = *func_ty.kind() {
+                            if self.record_implicit && Some(def_id) == tcx.lang_items().from_residual_fn() {
+                                self.record_mir_try_conversion(record, span, args);
+                            }
+                            // e.g. `Try::branch` of a `?`
+                            record = record.filter(|_| !is_hidden_desugaring(tcx, span, def_id));
+                        }
+
+                        let Some((caller, call_expr)) = record else {
+                            continue;
+                        };
+                        match resolved {
+                            Ok(Some(callee)) => {
+                                let operator = *call_source == CallSource::OverloadedOperator;
+                                if operator && self.is_autoderef(callee, span) {
+                                    if self.record_implicit {
+                                        self.implicit_calls.insert(Call {
+                                            call_expr,
+                                            call_expr_span: span,
+                                            caller: Some(caller),
+                                            caller_span: Some(definition_span(tcx, caller)),
+                                            callee: callee.def_id(),
+                                            callee_span: definition_span(tcx, callee.def_id()),
+                                        });
+                                    }
+                                    continue;
+                                }
+                                self.record_mir_call(call_expr, span, caller, callee, operator);
+                            }
+                            Ok(None) => self.record_mir_indirect_call(call_expr, span, caller, func_ty),
+                            Err((callee, reason)) => {
+                                self.unresolved_calls.insert(Unresolved {
+                                    call_expr,
+                                    call_expr_span: span,
+                                    caller: Some(caller),
+                                    callee: Some(callee),
+                                    reason,
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.for_loop_heads = for_loop_heads;
+    }
+
+    // MIR has the same calls for `*p` and for autoderef, only the former is in
+    // the source.
+    fn is_autoderef(&self, callee: Instance<'tcx>, span: Span) -> bool {
+        let lang_items = self.tcx.lang_items();
+        let is_deref = self
+            .tcx
+            .impl_of_method(callee.def_id())
+            .and_then(|impl_id| self.tcx.trait_id_of_impl(impl_id))
+            .is_some_and(|trait_id| Some(trait_id) == lang_items.deref_trait() || Some(trait_id) == lang_items.deref_mut_trait());
+        is_deref && !self.overloaded_derefs.contains(&span)
+    }
+
+    // Records the `Drop::drop` impls called when a value of type `ty` is
+    // dropped.
+    fn record_mir_drop(&mut self, call_expr: HirId, span: Span, caller: DefId, ty: Ty<'tcx>) {
+        let mut destructors = vec![];
+        self.destructors(ty, &mut destructors, &mut HashSet::new());
+        for destructor in destructors {
+            self.implicit_calls.insert(Call {
+                call_expr,
+                call_expr_span: span,
+                caller: Some(caller),
+                caller_span: Some(definition_span(self.tcx, caller)),
+                callee: destructor,
+                callee_span: definition_span(self.tcx, destructor),
+            });
+        }
+    }
+
+    // Records the `From::from` call of a `?`, given the generic arguments of
+    // the `FromResidual::from_residual` call it is desugared to.
+    fn record_mir_try_conversion(
+        &mut self,
+        record: Option<(DefId, HirId)>,
+        span: Span,
+        from_residual_args: ty::GenericArgsRef<'tcx>,
+    ) {
+        let Some((caller, call_expr)) = record else {
+            return;
+        };
+        let Some((from_fn, args)) = self.try_conversion(from_residual_args) else {
+            return;
+        };
+        if let Ok(Some(callee)) = Instance::try_resolve(self.tcx, ParamEnv::reveal_all(), from_fn, args) {
+            self.implicit_calls.insert(Call {
+                call_expr,
+                call_expr_span: span,
+                caller: Some(caller),
+                caller_span: Some(definition_span(self.tcx, caller)),
+                callee: callee.def_id(),
+                callee_span: definition_span(self.tcx, callee.def_id()),
+            });
+        }
+    }
+
+    fn record_mir_call(
+        &mut self,
+        call_expr: HirId,
+        span: Span,
+        caller: DefId,
+        callee: Instance<'tcx>,
+        operator: bool,
+    ) {
+        let tcx = self.tcx;
+        let call = |callee: DefId| Call {
+            call_expr,
+            call_expr_span: span,
+            caller: Some(caller),
+            caller_span: Some(definition_span(tcx, caller)),
+            callee,
+            callee_span: definition_span(tcx, callee),
+        };
+        match callee.def {
+            // a constructor passed as a fn, e.g. `map(Some)`
+            InstanceKind::Item(def_id) if matches!(tcx.def_kind(def_id), DefKind::Ctor(..)) => {
+                self.ctor_calls.insert(call(def_id));
+            }
+            InstanceKind::Virtual(def_id, _) => {
+                if tcx.trait_of_item(def_id).is_some_and(|trait_id| tcx.is_fn_trait(trait_id)) {
+                    // a call of a `dyn Fn`
+                    self.record_mir_indirect_call(call_expr, span, caller, callee.args.type_at(0));
+                } else {
+                    self.dynamic_calls.insert(call(def_id));
+                }
+            }
+            InstanceKind::FnPtrShim(_, ty) => match *ty.kind() {
+                // a fn item passed as `impl Fn`
+                ty::FnDef(def_id, _) => {
+                    self.static_calls.insert(call(def_id));
+                }
+                _ => self.record_mir_indirect_call(call_expr, span, caller, ty),
+            },
+            InstanceKind::ClosureOnceShim { .. } => {
+                self.record_mir_indirect_call(call_expr, span, caller, callee.args.type_at(0));
+            }
+            // `Future::poll` of an `async` body is a call of the coroutine
+            InstanceKind::Item(def_id) if tcx.def_kind(def_id) == DefKind::Closure && !tcx.is_coroutine(def_id) => {
+                self.indirect_calls.insert(IndirectCall {
+                    call_expr,
+                    call_expr_span: span,
+                    caller: Some(caller),
+                    closure: Some(def_id),
+                    callee_ty: tcx.def_path_str(def_id),
+                });
+            }
+            _ if operator => {
+                self.operator_calls.insert(call(callee.def_id()));
+            }
+            _ => {
+                self.static_calls.insert(call(callee.def_id()));
+            }
+        }
+    }
+
+    fn record_mir_indirect_call(&mut self, call_expr: HirId, span: Span, caller: DefId, callee_ty: Ty<'tcx>) {
+        let callee_ty = callee_ty.peel_refs();
+        let closure = match *callee_ty.kind() {
+            ty::Closure(def_id, _) => Some(def_id),
+            _ => None,
+        };
+        self.indirect_calls.insert(IndirectCall {
+            call_expr,
+            call_expr_span: span,
+            caller: Some(caller),
+            closure,
+            callee_ty: callee_ty.to_string(),
+        });
+    }
+}
+
+// How often the function `def_id` is on the path from an entry point to a call
+// of it in `caller`, counting that call. It grows without bounds with
+// polymorphic recursion, e.g. `f::<T>` calling `f::<(T,)>`.
+fn recursion_depth<'tcx>(
+    parents: &HashMap<Instance<'tcx>, Instance<'tcx>>,
+    caller: Instance<'tcx>,
+    def_id: DefId,
+) -> usize {
+    let mut depth = 1;
+    let mut instance = Some(caller);
+    while let Some(current) = instance {
+        if current.def_id() == def_id {
+            depth += 1;
+        }
+        instance = parents.get(&current).copied();
+    }
+    depth
+}